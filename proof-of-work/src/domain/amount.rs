@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+//base units in one coin.
+pub const COIN: u64 = 100_000_000;
+const DECIMALS: usize = 8;
+
+//an amount of coins as a whole number of base units, so sums come out the same on every node. arithmetic is
+//checked and fails instead of wrapping. it reads and writes as a decimal string of coins, e.g. "12.5".
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub const fn from_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    pub const fn units(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Result<Amount> {
+        self.0
+            .checked_add(other.0)
+            .map(Amount)
+            .ok_or_else(|| anyhow!("amount overflow: {} + {}", self, other))
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Result<Amount> {
+        self.0
+            .checked_sub(other.0)
+            .map(Amount)
+            .ok_or_else(|| anyhow!("amount underflow: {} - {}", self, other))
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Result<Amount> {
+        self.0
+            .checked_mul(factor)
+            .map(Amount)
+            .ok_or_else(|| anyhow!("amount overflow: {} * {}", self, factor))
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_sub(other.0))
+    }
+
+    pub fn checked_sum(amounts: impl IntoIterator<Item = Amount>) -> Result<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+impl fmt::Display for Amount {
+    //trailing zeros of the fraction are left out, and so is the point of a whole number of coins.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let coins = self.0 / COIN;
+        let fraction = self.0 % COIN;
+        if fraction == 0 {
+            return write!(f, "{}", coins);
+        }
+        let digits = format!("{:0width$}", fraction, width = DECIMALS);
+        write!(f, "{}.{}", coins, digits.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = anyhow::Error;
+
+    //a non-negative decimal number of coins with at most 8 decimals, like "100", "0.5" or "12.34567891".
+    fn from_str(s: &str) -> Result<Self> {
+        let (coins, fraction) = s.split_once('.').unwrap_or((s, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if coins.is_empty()
+            || !is_digits(coins)
+            || !is_digits(fraction)
+            || (s.contains('.') && fraction.is_empty())
+        {
+            return Err(anyhow!("invalid amount {:?}", s));
+        }
+        if fraction.len() > DECIMALS {
+            return Err(anyhow!(
+                "amount {:?} has more than {} decimals",
+                s,
+                DECIMALS
+            ));
+        }
+
+        let coins: u64 = coins
+            .parse()
+            .map_err(|_| anyhow!("amount {:?} is too large", s))?;
+        let fraction: u64 = format!("{:0<width$}", fraction, width = DECIMALS)
+            .parse()
+            .expect("checked to be at most 8 digits");
+        Amount(coins)
+            .checked_mul(COIN)
+            .and_then(|amount| amount.checked_add(Amount(fraction)))
+            .map_err(|_| anyhow!("amount {:?} is too large", s))
+    }
+}
+
+impl TryFrom<String> for Amount {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Amount> for String {
+    fn from(amount: Amount) -> Self {
+        amount.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_strings_round_trip_exactly() {
+        for s in [
+            "0",
+            "1",
+            "12.5",
+            "0.00000001",
+            "21000000",
+            "184467440737.09551615",
+        ] {
+            let amount: Amount = s.parse().unwrap();
+            assert_eq!(amount.to_string(), s);
+        }
+        assert_eq!("0.10000000".parse::<Amount>().unwrap().to_string(), "0.1");
+        assert_eq!(
+            "184467440737.09551615".parse::<Amount>().unwrap(),
+            Amount::MAX
+        );
+    }
+
+    #[test]
+    fn malformed_strings_are_rejected() {
+        for s in [
+            "",
+            "1.",
+            ".5",
+            "-1",
+            "+1",
+            "1e3",
+            "1.2.3",
+            " 1",
+            "0.123456789",
+        ] {
+            assert!(s.parse::<Amount>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        assert!("184467440737.09551616".parse::<Amount>().is_err());
+        assert!("99999999999999999999".parse::<Amount>().is_err());
+        assert!(Amount::MAX.checked_add(Amount::from_units(1)).is_err());
+        assert!(Amount::ZERO.checked_sub(Amount::from_units(1)).is_err());
+        assert!(Amount::MAX.checked_mul(2).is_err());
+    }
+
+    #[test]
+    fn serde_uses_the_decimal_string() {
+        let amount = Amount::from_units(COIN + COIN / 4);
+        assert_eq!(serde_json::to_string(&amount).unwrap(), "\"1.25\"");
+        assert_eq!(serde_json::from_str::<Amount>("\"1.25\"").unwrap(), amount);
+        assert!(serde_json::from_str::<Amount>("\"1.\"").is_err());
+    }
+}
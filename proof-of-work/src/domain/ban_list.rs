@@ -0,0 +1,151 @@
+use chrono::prelude::*;
+use std::collections::HashMap;
+
+pub const DEFAULT_BAN_THRESHOLD: u32 = 100;
+pub const DEFAULT_BAN_DURATION_SECS: i64 = 24 * 60 * 60;
+
+//protocol violations a peer can be blamed for; honest races such as a block on an unknown parent are not in here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    MalformedMessage,
+    BadFrame, //wrong magic or checksum
+    OversizedFrame,
+    UnexpectedMessage, //e.g. chain messages before the handshake
+    InvalidTransaction,
+    InvalidSignature,
+    InvalidBlock,
+    InvalidProofOfWork,
+}
+
+impl Misbehavior {
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::UnexpectedMessage => 10,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::BadFrame => 20,
+            Misbehavior::OversizedFrame => 50,
+            Misbehavior::InvalidSignature => 50,
+            Misbehavior::InvalidBlock => 50,
+            Misbehavior::InvalidProofOfWork => 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BanEntry {
+    pub host: String,
+    pub reason: String,
+    pub banned_at: DateTime<Utc>,
+    pub banned_until: DateTime<Utc>,
+}
+
+//misbehavior scores and bans, keyed by host (the address without its port) since inbound ports change with every connection.
+#[derive(Debug)]
+pub struct BanList {
+    threshold: u32,
+    duration: chrono::Duration,
+    scores: HashMap<String, u32>,
+    bans: HashMap<String, BanEntry>,
+}
+
+//"127.0.0.1:8050" -> "127.0.0.1", "[::1]:8050" -> "[::1]"
+pub fn host_of(addr: &str) -> &str {
+    addr.rsplit_once(':').map_or(addr, |(host, _)| host)
+}
+
+impl BanList {
+    pub fn new(threshold: u32, duration_secs: i64) -> Self {
+        Self {
+            threshold,
+            duration: chrono::Duration::seconds(duration_secs),
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    //adds the misbehavior to the host's score; returns the new ban if this pushed it over the threshold.
+    pub fn record(&mut self, addr: &str, misbehavior: Misbehavior) -> Option<BanEntry> {
+        let host = host_of(addr).to_string();
+        if self.is_banned(&host) {
+            return None;
+        }
+
+        let score = self.scores.entry(host.clone()).or_insert(0);
+        *score += misbehavior.score();
+        println!(
+            "Peer {} misbehaved ({:?}), ban score {}/{}",
+            addr, misbehavior, score, self.threshold
+        );
+        if *score < self.threshold {
+            return None;
+        }
+
+        self.scores.remove(&host);
+        let now = Utc::now();
+        let entry = BanEntry {
+            host: host.clone(),
+            reason: format!("{:?}", misbehavior),
+            banned_at: now,
+            banned_until: now + self.duration,
+        };
+        self.bans.insert(host, entry.clone());
+        Some(entry)
+    }
+
+    pub fn is_banned(&mut self, addr: &str) -> bool {
+        let host = host_of(addr);
+        match self.bans.get(host) {
+            Some(entry) if entry.banned_until > Utc::now() => true,
+            Some(_) => {
+                self.bans.remove(host); //expired
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&mut self) -> Vec<BanEntry> {
+        let now = Utc::now();
+        self.bans.retain(|_, entry| entry.banned_until > now);
+        let mut bans: Vec<BanEntry> = self.bans.values().cloned().collect();
+        bans.sort_by_key(|entry| entry.banned_at);
+        bans
+    }
+
+    pub fn unban(&mut self, host: &str) -> bool {
+        self.scores.remove(host);
+        self.bans.remove(host).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.scores.clear();
+        self.bans.clear();
+    }
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new(DEFAULT_BAN_THRESHOLD, DEFAULT_BAN_DURATION_SECS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_from_one_host_share_a_score() {
+        let mut bans = BanList::new(100, 60);
+        assert!(bans
+            .record("127.0.0.1:50123", Misbehavior::InvalidBlock)
+            .is_none());
+        let ban = bans
+            .record("127.0.0.1:50124", Misbehavior::InvalidBlock)
+            .unwrap();
+        assert_eq!(ban.host, "127.0.0.1");
+        assert!(bans.is_banned("127.0.0.1:7002"));
+        assert!(!bans.is_banned("127.0.0.2:7002"));
+        assert_eq!(host_of("[::1]:8050"), "[::1]");
+    }
+}
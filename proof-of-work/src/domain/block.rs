@@ -1,69 +1,105 @@
+use super::block_header::{self, BlockHeader, BLOCK_VERSION, HEADER_SIZE};
+use super::merkle::{self, MerkleProof};
+use super::ChainSpec;
+use super::Target;
 use super::Transaction;
-use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-#[derive(Debug, Serialize, Deserialize)]
+//largest block allowed, header and transactions included, in bytes. it fits in a frame of the default size; a node
+//started with a smaller --max-frame-size can't send or receive blocks near this limit.
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
+    pub version: u32,
     pub index: u64,
-    pub timestamp: String,
+    pub timestamp: u64, //seconds since the unix epoch
     pub transactions: Vec<Transaction>,
     pub previous_hash: String,
+    pub merkle_root: String, //commits to the transactions, so the header hash doesn't have to cover them one by one.
     pub hash: String,
     pub nonce: u64,
+    pub bits: u32, //compact form of the target the hash has to meet.
 }
 
 impl Block {
     pub fn new(index: u64, transactions: Vec<Transaction>, previous_hash: &str) -> Self {
         Self {
+            version: BLOCK_VERSION,
             index,
             transactions,
             previous_hash: previous_hash.to_string(),
-            timestamp: String::new(),
+            merkle_root: String::new(),
+            timestamp: 0,
             hash: String::new(),
             nonce: 0,
+            bits: 0,
         }
     }
 
-    pub fn genesis(difficulty: usize) -> Self {
+    //built purely from the chain spec so that every node of the network ends up with the same genesis hash.
+    pub fn genesis(spec: &ChainSpec) -> Self {
         let mut block = Block {
+            version: BLOCK_VERSION,
             index: 0,
-            transactions: Vec::new(),
-            previous_hash: String::from("0"),
-            timestamp: Utc::now().to_rfc3339(),
+            transactions: spec.genesis_transactions(),
+            previous_hash: hex::encode([0u8; 32]),
+            merkle_root: String::new(),
+            timestamp: spec.genesis_unix_timestamp(),
             hash: String::new(),
             nonce: 0,
+            bits: spec.initial_target().to_compact(),
         };
 
-        block.mine_block(difficulty);
+        //the timestamp is fixed, only the nonce moves, so the search is deterministic
+        block.merkle_root = block.calculate_merkle_root();
+        block.hash = block.calculate_hash();
+        while !block.meets_target() {
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
         block
     }
 
+    //the fixed-layout header the hash is computed over.
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            version: self.version,
+            height: self.index,
+            previous_hash: block_header::hash_bytes(&self.previous_hash),
+            merkle_root: block_header::hash_bytes(&self.merkle_root),
+            timestamp: self.timestamp,
+            bits: self.bits,
+            nonce: self.nonce,
+        }
+    }
+
     pub fn calculate_hash(&self) -> String {
-        let transaction_data: String = self
-            .transactions
-            .iter()
-            .map(|tx| tx.get_raw_transaction_data())
-            .collect();
+        self.header().hash()
+    }
 
-        let block_data = format!(
-            "{}{}{}{}{}",
-            self.index, self.timestamp, transaction_data, self.previous_hash, self.nonce
-        );
-        let mut hasher = Sha256::new();
-        hasher.update(block_data);
-        format!("{:x}", hasher.finalize())
+    //the header plus the serialized size of every transaction, what MAX_BLOCK_SIZE limits.
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.transactions.iter().map(|tx| tx.size()).sum::<usize>()
     }
 
-    // Perform Proof-of-Work by finding a hash that starts with a certain number of zeros
-    pub fn mine_block(&mut self, difficulty: usize) {
-        let prefix = "0".repeat(difficulty);
-        while !self.hash.starts_with(&prefix) {
-            self.nonce += 1;
-            self.timestamp = Utc::now().to_rfc3339();
-            self.hash = self.calculate_hash();
-        }
+    pub fn get_transaction_hashes(&self) -> Vec<String> {
+        self.transactions.iter().map(|tx| tx.get_hash()).collect()
+    }
+
+    pub fn calculate_merkle_root(&self) -> String {
+        merkle::merkle_root(&self.get_transaction_hashes())
+    }
+
+    //proof that the transaction with `tx_hash` is part of this block, checkable against `merkle_root` alone.
+    pub fn get_merkle_proof(&self, tx_hash: &str) -> Option<MerkleProof> {
+        let tx_hashes = self.get_transaction_hashes();
+        let index = tx_hashes.iter().position(|hash| hash == tx_hash)?;
+        merkle::build_proof(&tx_hashes, index)
+    }
 
-        println!("Block mined: {} with nonce: {}", self.hash, self.nonce);
+    //whether the hash is at or below the target the block claims in `bits`.
+    pub fn meets_target(&self) -> bool {
+        Target::from_compact(self.bits).is_ok_and(|target| target.is_met_by(&self.hash))
     }
 }
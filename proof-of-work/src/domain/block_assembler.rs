@@ -0,0 +1,172 @@
+use super::block::MAX_BLOCK_SIZE;
+use super::block_header::HEADER_SIZE;
+use super::utxo_set::UtxoSet;
+use super::validation::{self, StateOverlay};
+use super::{Amount, Transaction, TxOutput};
+
+//picks the transactions of the block at `height` from the pending ones: the highest fee rate first, as many as fit
+//under MAX_BLOCK_SIZE, each one valid on top of `utxos` and the ones picked before it. a transaction waiting for a
+//lower-paying one (an output it spends, or the previous nonce of its sender) is picked in a later pass, once that
+//one is in. returns them behind the miner's reward of `subsidy` plus fees, or None when none can go in.
+//`pending` already passed `check_transaction` when it was admitted, so only the state dependent rules are checked.
+pub fn assemble_transactions(
+    pending: &[Transaction],
+    utxos: &UtxoSet,
+    height: u64,
+    subsidy: Amount,
+    miner_address: String,
+) -> Option<Vec<Transaction>> {
+    //room for the reward with the longest amount there is
+    let longest_reward = TxOutput::new(miner_address.clone(), Amount::MAX);
+    let reward_size = Transaction::reward(height, vec![longest_reward]).size();
+    let mut room = MAX_BLOCK_SIZE.saturating_sub(HEADER_SIZE + reward_size);
+
+    //a stable sort, so equal fee rates keep their arrival order. the size and fee rate serialize the transaction,
+    //so each is worked out once.
+    let mut candidates: Vec<(usize, f64, &Transaction)> = pending
+        .iter()
+        .map(|transaction| (transaction.size(), transaction.fee_rate(), transaction))
+        .collect();
+    candidates.sort_by(|(_, a, _), (_, b, _)| b.total_cmp(a));
+
+    let mut overlay = StateOverlay::default();
+    let mut selected = Vec::new();
+    let mut amount = subsidy; //of the reward, the subsidy plus the fees of the picked transactions
+    loop {
+        let picked = selected.len();
+        candidates.retain(|&(size, _, transaction)| {
+            if size > room {
+                return false; //the room only shrinks, but a smaller one may still fit
+            }
+            if validation::check_transaction_state(transaction, height, utxos, &overlay).is_err() {
+                return true;
+            }
+            let Ok(reward_amount) = amount.checked_add(transaction.fee) else {
+                return false; //its fee can't fit in the reward
+            };
+            overlay.apply(transaction, height);
+            amount = reward_amount;
+            room -= size;
+            selected.push((*transaction).clone());
+            false
+        });
+        if selected.len() == picked {
+            break;
+        }
+    }
+    if selected.is_empty() {
+        return None;
+    }
+
+    //once issuance has ended a block without fees pays nothing, and a reward output has to be positive
+    let outputs = if amount.is_zero() {
+        Vec::new()
+    } else {
+        vec![TxOutput::new(miner_address, amount)]
+    };
+    let reward = Transaction::reward(height, outputs);
+    Some(std::iter::once(reward).chain(selected).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{Block, OutPoint, Wallet};
+
+    const CHAIN: &str = "chain";
+
+    fn coins(amount: &str) -> Amount {
+        amount.parse().unwrap()
+    }
+
+    //a utxo set in which each wallet owns a 100 coin output of the genesis block.
+    fn premine(wallets: &[&Wallet]) -> (UtxoSet, Vec<OutPoint>) {
+        let outputs = wallets
+            .iter()
+            .map(|wallet| TxOutput::new(wallet.address.clone(), coins("100")))
+            .collect();
+        let premine = Transaction::reward(0, outputs);
+        let outpoints = premine
+            .created_outputs()
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        let mut utxos = UtxoSet::new(100);
+        utxos.connect_block(&Block::new(0, vec![premine], &hex::encode([0u8; 32])));
+        (utxos, outpoints)
+    }
+
+    fn pay(
+        wallet: &Wallet,
+        input: OutPoint,
+        outputs: Vec<TxOutput>,
+        fee: &str,
+        nonce: u64,
+    ) -> Transaction {
+        let mut transaction = Transaction::new(wallet.address.clone(), vec![input], outputs)
+            .with_fee(coins(fee))
+            .with_nonce(nonce);
+        transaction.sign(&wallet.signing_key, CHAIN).unwrap();
+        transaction
+    }
+
+    fn to_someone(amount: &str) -> Vec<TxOutput> {
+        vec![TxOutput::new(Wallet::new().address, coins(amount))]
+    }
+
+    fn hashes(transactions: &[Transaction]) -> Vec<String> {
+        transactions.iter().map(|tx| tx.get_hash()).collect()
+    }
+
+    #[test]
+    fn the_highest_fee_rate_goes_first() {
+        let (alice, bob) = (Wallet::new(), Wallet::new());
+        let (utxos, outpoints) = premine(&[&alice, &bob]);
+        let cheap = pay(&alice, outpoints[0].clone(), to_someone("99"), "1", 0);
+        let dear = pay(&bob, outpoints[1].clone(), to_someone("98"), "2", 0);
+
+        let pending = [cheap.clone(), dear.clone()];
+        let block = assemble_transactions(&pending, &utxos, 1, coins("0.5"), alice.address.clone())
+            .unwrap();
+        assert_eq!(hashes(&block[1..]), hashes(&[dear, cheap]));
+        assert_eq!(block[0].total_output().unwrap(), coins("3.5"));
+    }
+
+    #[test]
+    fn a_transaction_waits_for_the_one_it_builds_on() {
+        let alice = Wallet::new();
+        let (utxos, outpoints) = premine(&[&alice]);
+        let mut outputs = to_someone("10");
+        outputs.push(TxOutput::new(alice.address.clone(), coins("89")));
+        let first = pay(&alice, outpoints[0].clone(), outputs, "0.01", 0);
+        let change = OutPoint {
+            txid: first.get_hash(),
+            vout: 1,
+        };
+        //pays more per byte, but spends the change of the first one and takes the next nonce
+        let second = pay(&alice, change, to_someone("80"), "9", 1);
+
+        let pending = [second.clone(), first.clone()];
+        let block = assemble_transactions(&pending, &utxos, 1, coins("0.5"), alice.address.clone())
+            .unwrap();
+        assert_eq!(hashes(&block[1..]), hashes(&[first, second]));
+    }
+
+    #[test]
+    fn transactions_that_dont_fit_are_left_out() {
+        let (alice, bob) = (Wallet::new(), Wallet::new());
+        let (utxos, outpoints) = premine(&[&alice, &bob]);
+        let small = pay(&alice, outpoints[0].clone(), to_someone("99"), "1", 0);
+        //a much higher fee rate, but more outputs than a block has room for
+        let split = vec![TxOutput::new(Wallet::new().address, coins("0.001")); MAX_BLOCK_SIZE / 64];
+        let huge = pay(&bob, outpoints[1].clone(), split, "50", 0);
+        assert!(huge.size() > MAX_BLOCK_SIZE);
+
+        let pending = [huge, small.clone()];
+        let block = assemble_transactions(&pending, &utxos, 1, coins("0.5"), alice.address.clone())
+            .unwrap();
+        assert_eq!(hashes(&block[1..]), hashes(&[small]));
+        assert!(
+            assemble_transactions(&pending[..1], &utxos, 1, coins("0.5"), alice.address).is_none()
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Result};
+use sha2::digest::generic_array::GenericArray;
+use sha2::{compress256, Digest, Sha256};
+
+//version of the header layout below; bump it when the layout changes.
+pub const BLOCK_VERSION: u32 = 1;
+
+//header layout (all integers big-endian, 96 bytes in total):
+//  version        4 bytes  u32
+//  height         8 bytes  u64
+//  previous hash 32 bytes  sha256 of the parent's header
+//  merkle root   32 bytes  root of the transaction hashes
+//  timestamp      8 bytes  u64, seconds since the unix epoch
+//  target         4 bytes  u32, compact "bits" form
+//  nonce          8 bytes  u64
+//the block hash is sha256 over exactly these bytes, so every field has a fixed place and width.
+pub const HEADER_SIZE: usize = 96;
+
+//sha256's initial hash value.
+const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub height: u64,
+    pub previous_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub timestamp: u64,
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.version.to_be_bytes());
+        bytes[4..12].copy_from_slice(&self.height.to_be_bytes());
+        bytes[12..44].copy_from_slice(&self.previous_hash);
+        bytes[44..76].copy_from_slice(&self.merkle_root);
+        bytes[76..84].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[84..88].copy_from_slice(&self.bits.to_be_bytes());
+        bytes[88..96].copy_from_slice(&self.nonce.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let bytes: &[u8; HEADER_SIZE] = bytes.try_into().map_err(|_| {
+            anyhow!(
+                "Block header must be {} bytes, got {}",
+                HEADER_SIZE,
+                bytes.len()
+            )
+        })?;
+        Ok(Self {
+            version: u32::from_be_bytes(bytes[0..4].try_into()?),
+            height: u64::from_be_bytes(bytes[4..12].try_into()?),
+            previous_hash: bytes[12..44].try_into()?,
+            merkle_root: bytes[44..76].try_into()?,
+            timestamp: u64::from_be_bytes(bytes[76..84].try_into()?),
+            bits: u32::from_be_bytes(bytes[84..88].try_into()?),
+            nonce: u64::from_be_bytes(bytes[88..96].try_into()?),
+        })
+    }
+
+    pub fn digest(&self) -> [u8; 32] {
+        Sha256::digest(self.encode()).into()
+    }
+
+    //hex-encoded sha256 of the encoded header.
+    pub fn hash(&self) -> String {
+        hex::encode(self.digest())
+    }
+}
+
+//sha256 works on 64-byte chunks, and the first chunk of a header (version, height, previous hash and the start of
+//the merkle root) doesn't change while mining. its compressed state is computed once, and each attempt only
+//compresses the second chunk, which holds the timestamp and nonce, instead of re-encoding and hashing everything.
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderMidstate {
+    state: [u32; 8],
+    tail: [u8; 64], //header bytes 64..96 followed by sha256 padding for a 96-byte message
+}
+
+impl HeaderMidstate {
+    pub fn new(header: &BlockHeader) -> Self {
+        let bytes = header.encode();
+        let mut state = SHA256_IV;
+        compress256(&mut state, &[*GenericArray::from_slice(&bytes[..64])]);
+
+        let mut tail = [0u8; 64];
+        tail[..32].copy_from_slice(&bytes[64..]);
+        tail[32] = 0x80;
+        tail[56..].copy_from_slice(&(HEADER_SIZE as u64 * 8).to_be_bytes());
+        Self { state, tail }
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.tail[12..20].copy_from_slice(&timestamp.to_be_bytes());
+    }
+
+    pub fn set_nonce(&mut self, nonce: u64) {
+        self.tail[24..32].copy_from_slice(&nonce.to_be_bytes());
+    }
+
+    //same as `BlockHeader::digest` for the header with the current timestamp and nonce.
+    pub fn digest(&self) -> [u8; 32] {
+        let mut state = self.state;
+        compress256(&mut state, &[*GenericArray::from_slice(&self.tail)]);
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+//hashes are kept as hex strings elsewhere; one that isn't 32 bytes of hex encodes as zeros,
+//which then fails the linkage or merkle root checks.
+pub fn hash_bytes(hash: &str) -> [u8; 32] {
+    hex::decode(hash)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .unwrap_or([0u8; 32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_header() -> BlockHeader {
+        BlockHeader {
+            version: BLOCK_VERSION,
+            height: 0x0102030405060708,
+            previous_hash: [0xaa; 32],
+            merkle_root: [0xbb; 32],
+            timestamp: 0x1112131415161718,
+            bits: 0x1d00ffff,
+            nonce: 0x2122232425262728,
+        }
+    }
+
+    #[test]
+    fn decode_reverses_encode() {
+        let header = sample_header();
+        assert_eq!(BlockHeader::decode(&header.encode()).unwrap(), header);
+        assert!(BlockHeader::decode(&header.encode()[..HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn fields_sit_at_their_documented_offsets() {
+        let bytes = sample_header().encode();
+        assert_eq!(bytes[0..4], [0, 0, 0, 1]);
+        assert_eq!(bytes[4..12], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(bytes[12..44], [0xaa; 32]);
+        assert_eq!(bytes[44..76], [0xbb; 32]);
+        assert_eq!(
+            bytes[76..84],
+            [0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18]
+        );
+        assert_eq!(bytes[84..88], [0x1d, 0x00, 0xff, 0xff]);
+        assert_eq!(
+            bytes[88..96],
+            [0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28]
+        );
+    }
+
+    #[test]
+    fn midstate_digest_matches_the_full_hash() {
+        let mut header = sample_header();
+        let mut midstate = HeaderMidstate::new(&header);
+        assert_eq!(midstate.digest(), header.digest());
+
+        header.timestamp = 1_735_689_600;
+        header.nonce = 42;
+        midstate.set_timestamp(header.timestamp);
+        midstate.set_nonce(header.nonce);
+        assert_eq!(midstate.digest(), header.digest());
+    }
+}
@@ -0,0 +1,163 @@
+use super::Block;
+use super::Target;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+//every block we have validated the header of, whether it sits on the active chain or on a side branch.
+#[derive(Debug, Clone)]
+pub struct BlockEntry {
+    pub block: Block,
+    pub next_target: Target,   //target its children have to meet.
+    pub cumulative_work: u128, //expected number of hashes needed to build the branch up to this block.
+}
+
+#[derive(Debug)]
+pub struct BlockTree {
+    entries: HashMap<String, BlockEntry>,
+    children: HashMap<String, Vec<String>>,
+}
+
+impl BlockTree {
+    pub fn new(genesis: Block, target: Target) -> Self {
+        let mut entries = HashMap::new();
+        entries.insert(
+            genesis.hash.clone(),
+            BlockEntry {
+                block: genesis,
+                next_target: target,
+                cumulative_work: target.work(),
+            },
+        );
+
+        Self {
+            entries,
+            children: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&BlockEntry> {
+        self.entries.get(hash)
+    }
+
+    //the block's parent must already be in the tree; its cumulative work is derived from the parent's.
+    pub fn insert(&mut self, block: Block, next_target: Target) -> Result<&BlockEntry> {
+        let parent = self
+            .entries
+            .get(&block.previous_hash)
+            .ok_or_else(|| anyhow!("unknown parent block {}", block.previous_hash))?;
+
+        let target = parent.next_target;
+        let cumulative_work = parent.cumulative_work.saturating_add(target.work());
+        let hash = block.hash.clone();
+
+        self.children
+            .entry(block.previous_hash.clone())
+            .or_default()
+            .push(hash.clone());
+        self.entries.insert(
+            hash.clone(),
+            BlockEntry {
+                block,
+                next_target,
+                cumulative_work,
+            },
+        );
+
+        Ok(&self.entries[&hash])
+    }
+
+    //drops a block that turned out to be invalid together with everything built on top of it.
+    pub fn remove_with_descendants(&mut self, hash: &str) {
+        let mut stack = vec![hash.to_string()];
+        while let Some(hash) = stack.pop() {
+            if let Some(children) = self.children.remove(&hash) {
+                stack.extend(children);
+            }
+            if let Some(entry) = self.entries.remove(&hash) {
+                if let Some(siblings) = self.children.get_mut(&entry.block.previous_hash) {
+                    siblings.retain(|sibling| *sibling != hash);
+                }
+            }
+        }
+    }
+
+    //the entry with the most cumulative work; on a tie, any of them.
+    pub fn best_tip(&self) -> &BlockEntry {
+        self.entries
+            .values()
+            .max_by_key(|entry| entry.cumulative_work)
+            .expect("the tree always holds the genesis block")
+    }
+
+    //up to `count` entries ending at `hash`, oldest first.
+    pub fn ancestors(&self, hash: &str, count: usize) -> Vec<&BlockEntry> {
+        let mut ancestors = Vec::new();
+        let mut cursor = self.entries.get(hash);
+        while let Some(entry) = cursor {
+            if ancestors.len() == count {
+                break;
+            }
+            ancestors.push(entry);
+            cursor = self.entries.get(&entry.block.previous_hash);
+        }
+        ancestors.reverse();
+        ancestors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(index: u64, hash: &str, previous_hash: &str) -> Block {
+        let mut block = Block::new(index, Vec::new(), previous_hash);
+        block.hash = hash.to_string();
+        block
+    }
+
+    //genesis <- a1 <- a2, and genesis <- b1 <- b2 <- b3
+    fn tree() -> BlockTree {
+        let mut tree = BlockTree::new(block(0, "genesis", ""), Target::MAX);
+        for (index, hash, previous_hash) in [
+            (1, "a1", "genesis"),
+            (2, "a2", "a1"),
+            (1, "b1", "genesis"),
+            (2, "b2", "b1"),
+            (3, "b3", "b2"),
+        ] {
+            tree.insert(block(index, hash, previous_hash), Target::MAX)
+                .unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn work_accumulates_along_a_branch() {
+        let mut tree = tree();
+        let work = Target::MAX.work();
+        assert_eq!(tree.get("a2").unwrap().cumulative_work, 3 * work);
+        assert_eq!(tree.get("b3").unwrap().cumulative_work, 4 * work);
+        assert_eq!(tree.best_tip().block.hash, "b3");
+        assert!(tree.insert(block(1, "c1", "unknown"), Target::MAX).is_err());
+    }
+
+    #[test]
+    fn removing_a_block_drops_its_descendants_only() {
+        let mut tree = tree();
+        tree.remove_with_descendants("b2");
+        assert!(!tree.contains("b2") && !tree.contains("b3"));
+        assert!(tree.contains("b1"));
+        assert_eq!(tree.best_tip().block.hash, "a2");
+
+        let ancestors: Vec<&str> = tree
+            .ancestors("a2", 2)
+            .iter()
+            .map(|entry| entry.block.hash.as_str())
+            .collect();
+        assert_eq!(ancestors, ["a1", "a2"]);
+    }
+}
@@ -1,191 +1,1001 @@
+use super::ban_list::Misbehavior;
+use super::block_assembler;
+use super::block_tree::BlockTree;
+use super::sync::{ENVELOPE_OVERHEAD, SYNC_BATCH_SIZE};
+use super::utxo_set::MAX_REORG_DEPTH;
+use super::validation::{self, Rule, StateOverlay, ValidationError};
+use super::Amount;
 use super::Block;
+use super::ChainSpec;
+use super::MerkleProof;
+use super::Message;
 use super::Network;
-use super::Transaction;
+use super::Target;
+use super::{OutPoint, Transaction, TxOutput, UtxoSet};
+use super::{Retarget, RetargetSample};
 
-use anyhow::{anyhow, Error, Ok, Result};
-use chrono::prelude::*;
+use anyhow::{anyhow, Ok, Result};
+use chrono::Utc;
 use std::collections::HashSet;
+use tokio::sync::watch;
+
+//most transactions waiting for a block; new ones are refused while the pool is full.
+pub const MAX_PENDING_TRANSACTIONS: usize = 5000;
 
 pub struct Blockchain {
-    pub chain: Vec<Block>,
+    pub chain: Vec<Block>, //the active branch, from genesis to the tip with the most cumulative work.
+    pub block_tree: BlockTree,
     pub pending_transactions: Vec<Transaction>,
-    pub difficulty: usize,
+    pending_hashes: HashSet<String>, //of the pending transactions, to spot one relayed to us again.
+    pending_overlay: StateOverlay, //what the pending transactions change on top of `utxos`, in the next block.
+    pub utxos: UtxoSet,            //unspent outputs of the active chain.
+    pub target: Target,            //target the next block on the tip has to meet.
+    pub pow_limit: Target,         //the easiest target ever allowed, from the chain spec.
     pub target_time: u64,
+    pub retarget: Retarget,
     pub peers: HashSet<String>,
     pub network: Network,
+    pub chain_spec: ChainSpec,
+    pub needs_sync: bool, //set when a peer sent a block whose parent we don't have.
+    tip: watch::Sender<String>,
 }
 
 impl Blockchain {
-    pub fn new(difficulty: usize, target_time: u64) -> Result<Self> {
-        if difficulty == 0 {
-            return Err(anyhow!("Difficulty must be greater than 0"));
-        }
-        if target_time == 0 {
+    pub fn new(chain_spec: ChainSpec) -> Result<Self> {
+        if chain_spec.target_time == 0 {
             return Err(anyhow!("Target time must be greater than 0"));
         }
 
+        let target = chain_spec.initial_target();
+        let genesis = Block::genesis(&chain_spec);
+        let tip = watch::Sender::new(genesis.hash.clone());
+        let mut utxos = UtxoSet::new(chain_spec.monetary_policy.coinbase_maturity);
+        utxos.connect_block(&genesis);
+        println!("Genesis block of {}: {}", chain_spec.name, genesis.hash);
         Ok(Self {
-            difficulty,
-            target_time,
-            chain: vec![Block::genesis(difficulty)],
+            target,
+            pow_limit: target,
+            target_time: chain_spec.target_time,
+            retarget: chain_spec.retarget,
+            block_tree: BlockTree::new(genesis.clone(), target),
+            chain: vec![genesis],
             pending_transactions: Vec::new(),
+            pending_hashes: HashSet::new(),
+            pending_overlay: StateOverlay::default(),
+            utxos,
             peers: HashSet::new(),
             network: Network::new(),
+            needs_sync: false,
+            tip,
+            chain_spec,
         })
     }
 
+    //the genesis hash identifies the network: nodes only talk to peers that share it.
+    pub fn get_genesis_hash(&self) -> &str {
+        &self.chain[0].hash
+    }
+
     pub fn set_network(&mut self, network: Network) {
         self.network = network;
+        self.network.set_best_height(self.get_latest_block().index);
     }
 
     pub async fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
-        if transaction.amount <= 0.0 {
-            return Err(anyhow!("Invalid transaction: amount must be positive!"));
+        if self.is_pool_full() {
+            return Err(anyhow!("Transaction rejected: the pool is full"));
         }
+        self.validate_transaction(&transaction)
+            .map_err(|(rule, detail)| anyhow!("Transaction rejected: {} ({})", rule, detail))?;
+        self.admit_transaction(transaction).await;
+        Ok(())
+    }
 
-        //check sender balance
-        let sender_balance = self.get_balance(&transaction.sender);
-        println!("sender: {} balance:{}", transaction.sender, sender_balance);
+    //queues an already validated transaction for the next block and relays it.
+    async fn admit_transaction(&mut self, transaction: Transaction) {
+        self.push_pending(transaction);
 
-        if sender_balance < transaction.amount {
-            return Err(anyhow!(
-                "Transaction rejected: Insufficient funds for {}",
-                transaction.sender
+        //send the transaction to all peers
+        self.network
+            .broadcast(&Message::Tx(
+                self.pending_transactions.last().unwrap().clone(),
+            ))
+            .await;
+    }
+
+    //a pending transaction has to be valid in the next block on our tip, after the ones already pending: it may spend
+    //their outputs but none they spend, and has to carry its sender's next nonce.
+    fn validate_transaction(&self, transaction: &Transaction) -> Result<(), (Rule, String)> {
+        if transaction.is_reward {
+            return Err((
+                Rule::ExtraReward,
+                String::from("rewards are only paid by blocks"),
             ));
         }
+        let height = self.get_latest_block().index + 1;
+        validation::check_transaction(
+            transaction,
+            height,
+            self.get_genesis_hash(),
+            &self.utxos,
+            &self.pending_overlay,
+        )
+    }
 
+    fn is_pool_full(&self) -> bool {
+        self.pending_transactions.len() >= MAX_PENDING_TRANSACTIONS
+    }
+
+    //adds a transaction valid on top of the pending ones for the next block.
+    fn push_pending(&mut self, transaction: Transaction) {
+        let height = self.get_latest_block().index + 1;
+        self.pending_overlay.apply(&transaction, height);
+        self.pending_hashes.insert(transaction.get_hash());
         self.pending_transactions.push(transaction);
+    }
 
-        //serialize the transaction once
-        let serialized_transaction =
-            serde_json::to_string(&self.pending_transactions.last().unwrap())?;
+    //empties the pool, e.g. to check its transactions again after the tip moved.
+    fn take_pending(&mut self) -> Vec<Transaction> {
+        self.pending_hashes.clear();
+        self.pending_overlay = StateOverlay::default();
+        std::mem::take(&mut self.pending_transactions)
+    }
 
-        //send the transaction to all peers
-        for peer in self.network.get_peers().await {
-            if let Err(err) = self
-                .network
-                .send_message(&peer, &serialized_transaction)
-                .await
-            {
-                eprintln!("Error:Failed to send transaction to {}: {}", peer, err);
+    //entry point for chain-level messages coming from peers; the returned message, if any, is sent back as the reply.
+    pub async fn handle_message(&mut self, from: &str, message: Message) -> Option<Message> {
+        match message {
+            Message::Tx(transaction) => self.receive_transaction(from, transaction).await,
+            Message::Block(block) => self.receive_block(from, block).await,
+            Message::GetBlocks { locator, limit } => {
+                let max_bytes = self
+                    .network
+                    .codec()
+                    .max_frame_size()
+                    .saturating_sub(ENVELOPE_OVERHEAD);
+                Some(Message::Blocks(self.get_blocks_after_locator(
+                    &locator,
+                    limit.min(SYNC_BATCH_SIZE),
+                    max_bytes,
+                )))
+            }
+            Message::Blocks(blocks) => {
+                println!("Received {} blocks from {}", blocks.len(), from);
+                for block in blocks {
+                    if let Some(reject) = self.receive_block(from, block).await {
+                        return Some(reject);
+                    }
+                }
+                None
+            }
+            //protocol-level messages are answered by the network itself.
+            _ => None,
+        }
+    }
+
+    async fn receive_transaction(
+        &mut self,
+        from: &str,
+        transaction: Transaction,
+    ) -> Option<Message> {
+        //don't relay it again. one that is already confirmed fails on its nonce, which doesn't count against the peer
+        if self.pending_hashes.contains(&transaction.get_hash()) {
+            return None;
+        }
+        if self.is_pool_full() {
+            return Some(Message::reject("transaction pool is full"));
+        }
+
+        if let Err((rule, detail)) = self.validate_transaction(&transaction) {
+            if let Some(misbehavior) = Self::misbehavior_for_transaction(rule) {
+                self.network.report_misbehavior(from, misbehavior).await;
+            }
+            return Some(Message::reject(format!(
+                "Transaction rejected: {} ({})",
+                rule, detail
+            )));
+        }
+        self.admit_transaction(transaction).await;
+        None
+    }
+
+    //no honest node relays a transaction that breaks a rule on its own; one that only conflicts with our chain state
+    //may be valid on a chain the peer has seen and we haven't yet.
+    fn misbehavior_for_transaction(rule: Rule) -> Option<Misbehavior> {
+        match rule {
+            Rule::MissingInput
+            | Rule::Nonce
+            | Rule::InsufficientFunds
+            | Rule::DoubleSpend
+            | Rule::ImmatureReward => None,
+            Rule::InvalidSignature | Rule::MissingSignature => Some(Misbehavior::InvalidSignature),
+            _ => Some(Misbehavior::InvalidTransaction),
+        }
+    }
+
+    pub fn get_balance(&self, address: &str) -> Amount {
+        self.utxos.balance(address) //premined funds are outputs of the genesis block.
+    }
+
+    //outputs of `address` a new transaction can spend: mature, and not spent by a pending transaction already.
+    pub fn get_spendable_outputs(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        self.utxos
+            .spendable_outputs(address, self.get_latest_block().index + 1)
+            .into_iter()
+            .filter(|(outpoint, _)| !self.pending_overlay.is_spent(outpoint))
+            .collect()
+    }
+
+    //the nonce the next transaction of `address` has to carry, counting its pending ones.
+    pub fn get_next_nonce(&self, address: &str) -> u64 {
+        self.pending_overlay.next_nonce(address, &self.utxos)
+    }
+
+    //the subsidy the monetary policy pays the miner of the block at `height`.
+    pub fn get_subsidy(&self, height: u64) -> Amount {
+        self.chain_spec
+            .monetary_policy
+            .subsidy(height, self.chain_spec.premine_total())
+    }
+
+    //coins in existence once the block at `height` was connected, or None if our chain isn't that long yet.
+    pub fn get_circulating_supply(&self, height: u64) -> Option<Amount> {
+        if height > self.get_latest_block().index {
+            return None;
+        }
+        Some(
+            self.chain_spec
+                .monetary_policy
+                .supply(height, self.chain_spec.premine_total()),
+        )
+    }
+
+    //the next block to mine on our tip: the miner's reward followed by the pending transactions paying the most fee
+    //per byte that fit in a block. they stay pending until the block is connected, in case mining is cancelled.
+    pub fn create_block_template(&self, miner_address: String) -> Option<Block> {
+        let height = self.get_latest_block().index + 1;
+        let Some(transactions) = block_assembler::assemble_transactions(
+            &self.pending_transactions,
+            &self.utxos,
+            height,
+            self.get_subsidy(height),
+            miner_address,
+        ) else {
+            println!("Warning: No transactions to add!");
+            return None;
+        };
+
+        let previous_block = self.get_latest_block();
+        let mut template = Block::new(height, transactions, &previous_block.hash);
+        //the earliest timestamp the block may carry; miners only ever move it forward
+        let median_time_past = self.get_median_time_past(&previous_block.hash);
+        template.timestamp = (Utc::now().timestamp() as u64).max(median_time_past + 1);
+        template.bits = self.target.to_compact();
+        template.merkle_root = template.calculate_merkle_root();
+        Some(template)
+    }
+
+    //takes a block solved by our miner, connects it and announces it so peers can extend their chain with it.
+    pub async fn submit_block(&mut self, block: Block) -> Result<()> {
+        self.accept_block(block.clone())?;
+        self.network.broadcast(&Message::Block(block)).await;
+        Ok(())
+    }
+
+    //notified with the new tip hash whenever the active chain changes.
+    pub fn subscribe_tip(&self) -> watch::Receiver<String> {
+        self.tip.subscribe()
+    }
+
+    async fn receive_block(&mut self, from: &str, block: Block) -> Option<Message> {
+        if self.block_tree.contains(&block.hash) {
+            return None; //already known, don't relay it again.
+        }
+
+        if !self.block_tree.contains(&block.previous_hash) {
+            //we are behind the sender; catch up through a sync instead of guessing at the missing blocks.
+            println!(
+                "Block {} has an unknown parent, scheduling a sync",
+                block.index
+            );
+            self.needs_sync = true;
+            return None;
+        }
+
+        if let Err(err) = self.accept_block(block.clone()) {
+            eprintln!("Error: Rejected block {}: {}", block.index, err);
+            if let Some(misbehavior) = Self::misbehavior_for_rejection(&err) {
+                self.network.report_misbehavior(from, misbehavior).await;
             }
+            return Some(Message::reject(err.to_string()));
+        }
+
+        self.network.broadcast(&Message::Block(block)).await;
+        None
+    }
+
+    //how badly a peer misbehaved by sending a block we rejected; work that was never done weighs the most. a block
+    //from the future is no one's fault when the clocks disagree.
+    pub fn misbehavior_for_rejection(err: &anyhow::Error) -> Option<Misbehavior> {
+        match err.downcast_ref::<ValidationError>().map(|err| err.rule) {
+            Some(Rule::TimestampTooNew) => None,
+            Some(Rule::Hash | Rule::Target | Rule::ProofOfWork) => {
+                Some(Misbehavior::InvalidProofOfWork)
+            }
+            Some(Rule::InvalidSignature) => Some(Misbehavior::InvalidSignature),
+            _ => Some(Misbehavior::InvalidBlock),
+        }
+    }
+
+    //stores a block in the tree and moves the active chain onto its branch if that branch now has the most work.
+    pub fn accept_block(&mut self, block: Block) -> Result<()> {
+        let parent = self
+            .block_tree
+            .get(&block.previous_hash)
+            .ok_or_else(|| anyhow!("unknown parent block {}", block.previous_hash))?;
+        validation::check_header(
+            &block,
+            &parent.block,
+            parent.next_target,
+            self.get_median_time_past(&block.previous_hash),
+            Utc::now().timestamp() as u64,
+        )?;
+
+        let tip_work = self.get_tip_work();
+        let next_target = self.calculate_next_target(&block);
+        let entry = self.block_tree.insert(block.clone(), next_target)?;
+        let cumulative_work = entry.cumulative_work;
+
+        if block.previous_hash == self.get_latest_block().hash {
+            if let Err(err) = self.validate_block_transactions(&block) {
+                self.block_tree.remove_with_descendants(&block.hash);
+                return Err(err);
+            }
+            println!("Accepted block {}: {}", block.index, block.hash);
+            self.connect_block(block);
+        } else if cumulative_work > tip_work {
+            self.reorganize(&block.hash)?;
+        } else {
+            println!(
+                "Stored block {} on a side branch: {}",
+                block.index, block.hash
+            );
         }
 
         Ok(())
     }
 
-    pub fn get_balance(&self, address: &str) -> f64 {
-        let mut balance = 200.0; //default balance for demo purposes.
-        for block in &self.chain {
-            for transaction in &block.transactions {
-                if transaction.sender == address {
-                    balance -= transaction.amount;
+    //appends a block whose parent is the current tip.
+    fn connect_block(&mut self, block: Block) {
+        let confirmed: HashSet<String> =
+            block.transactions.iter().map(|tx| tx.get_hash()).collect();
+        self.utxos.connect_block(&block);
+        self.chain.push(block);
+        self.tip_changed();
+
+        //drop the pending transactions the block already confirmed, and those it made invalid: spending the same
+        //outputs, reusing a nonce, or building on one of those
+        let candidates: Vec<Transaction> = self
+            .take_pending()
+            .into_iter()
+            .filter(|tx| !confirmed.contains(&tx.get_hash()))
+            .collect();
+        self.revalidate_pending(candidates, "Dropped transaction after new block");
+    }
+
+    //puts the candidates back in the pool, in order, keeping those still valid on top of our tip.
+    fn revalidate_pending(&mut self, candidates: Vec<Transaction>, reason: &str) {
+        for transaction in candidates {
+            if self.is_pool_full() {
+                eprintln!("{}: the pool is full", reason);
+                continue;
+            }
+            match self.validate_transaction(&transaction) {
+                Result::Ok(()) => self.push_pending(transaction),
+                Err((rule, detail)) => eprintln!("{}: {} ({})", reason, rule, detail),
+            }
+        }
+    }
+
+    //switches the active chain to the branch ending at `new_tip`, replaying its transactions on top of the fork point.
+    fn reorganize(&mut self, new_tip: &str) -> Result<()> {
+        let mut branch = Vec::new();
+        let mut cursor = new_tip.to_string();
+        loop {
+            let entry = self
+                .block_tree
+                .get(&cursor)
+                .ok_or_else(|| anyhow!("branch is missing block {}", cursor))?;
+            if self.is_on_active_chain(&entry.block) {
+                break;
+            }
+            branch.push(entry.block.clone());
+            cursor = entry.block.previous_hash.clone();
+        }
+        branch.reverse();
+
+        let fork_index = branch[0].index as usize - 1;
+        let depth = (self.chain.len() - 1 - fork_index) as u64;
+        if depth > MAX_REORG_DEPTH {
+            return Err(anyhow!(
+                "reorganization would disconnect {} blocks, more than the limit of {}",
+                depth,
+                MAX_REORG_DEPTH
+            ));
+        }
+        let disconnected = self.chain.split_off(fork_index + 1);
+        for block in disconnected.iter().rev() {
+            self.utxos.disconnect_block(block)?;
+        }
+        println!(
+            "Reorganizing: disconnecting {} blocks and connecting {} blocks after block {}",
+            disconnected.len(),
+            branch.len(),
+            fork_index
+        );
+
+        for block in &branch {
+            if let Err(err) = self.validate_block_transactions(block) {
+                //the heavier branch is invalid: forget the block and what builds on it, and put the old chain back
+                self.block_tree.remove_with_descendants(&block.hash);
+                for connected in self.chain.split_off(fork_index + 1).iter().rev() {
+                    self.utxos.disconnect_block(connected)?;
                 }
-                if transaction.receiver == address {
-                    balance += transaction.amount;
+                for block in &disconnected {
+                    self.utxos.connect_block(block);
                 }
+                self.chain.extend(disconnected);
+                self.tip_changed();
+
+                //the valid start of the branch, or another one, may still have more work than the old chain.
+                //every failed attempt removes blocks from the tree, so this ends.
+                let best = self.block_tree.best_tip();
+                if best.cumulative_work > self.get_tip_work() {
+                    let best = best.block.hash.clone();
+                    if let Err(err) = self.reorganize(&best) {
+                        eprintln!("Error: {}", err);
+                    }
+                }
+                return Err(anyhow!(
+                    "reorganization aborted, block {} is invalid: {}",
+                    block.index,
+                    err
+                ));
             }
+            self.utxos.connect_block(block);
+            self.chain.push(block.clone());
         }
+        self.tip_changed();
+
+        //transactions of the abandoned blocks go back to the pool unless the new branch already has them
+        let confirmed: HashSet<String> = branch
+            .iter()
+            .flat_map(|block| block.transactions.iter())
+            .map(|tx| tx.get_hash())
+            .collect();
+        let orphaned = disconnected
+            .into_iter()
+            .flat_map(|block| block.transactions.into_iter())
+            .filter(|tx| !tx.is_reward);
+        let candidates: Vec<Transaction> = orphaned
+            .chain(self.take_pending())
+            .filter(|tx| !confirmed.contains(&tx.get_hash()))
+            .collect();
+        self.revalidate_pending(candidates, "Dropped transaction after reorganization");
 
-        balance
+        println!(
+            "Reorganization complete, new tip is block {}: {}",
+            self.get_latest_block().index,
+            self.get_latest_block().hash
+        );
+        Ok(())
     }
 
-    pub fn add_block(&mut self, miner_address: String) {
-        if self.pending_transactions.is_empty() {
-            println!("Warning: No transactions to add!");
-            return;
+    //blocks of our active chain following the first locator hash we recognise, or from genesis if none match.
+    //the batch is cut short once the next block would take its serialized size past `max_bytes`, so the reply
+    //always fits in a frame; a block that doesn't fit on its own leaves the batch empty.
+    pub fn get_blocks_after_locator(
+        &self,
+        locator: &[String],
+        limit: u32,
+        max_bytes: usize,
+    ) -> Vec<Block> {
+        let start = locator
+            .iter()
+            .filter_map(|hash| self.block_tree.get(hash))
+            .find(|entry| self.is_on_active_chain(&entry.block))
+            .map_or(0, |entry| entry.block.index as usize + 1);
+
+        let mut blocks = Vec::new();
+        let mut size = 0;
+        for block in self.chain.iter().skip(start).take(limit as usize) {
+            let block_size = serde_json::to_vec(block).map_or(usize::MAX, |bytes| bytes.len() + 1); //and its comma
+            if size + block_size > max_bytes {
+                break;
+            }
+            size += block_size;
+            blocks.push(block.clone());
         }
+        blocks
+    }
 
-        //reward the miner
-        let reward_transaction = Transaction::reward(miner_address, 0.5);
-        self.pending_transactions.insert(0, reward_transaction);
-
-        //take ownership of pending transactions to avoid cloning
-        let transactions = std::mem::take(&mut self.pending_transactions);
+    //finds the transaction on the active chain and proves it against its block's merkle root.
+    pub fn get_transaction_proof(&self, tx_hash: &str) -> Option<(&Block, MerkleProof)> {
+        self.chain
+            .iter()
+            .rev()
+            .find_map(|block| block.get_merkle_proof(tx_hash).map(|proof| (block, proof)))
+    }
 
-        let previous_block = self.get_latest_block();
-        let mut new_block =
-            Block::new(previous_block.index + 1, transactions, &previous_block.hash);
+    fn is_on_active_chain(&self, block: &Block) -> bool {
+        self.chain
+            .get(block.index as usize)
+            .is_some_and(|active| active.hash == block.hash)
+    }
 
-        new_block.mine_block(self.difficulty);
-        self.chain.push(new_block);
-        self.adjust_difficulty();
+    fn get_tip_work(&self) -> u128 {
+        self.block_tree
+            .get(&self.get_latest_block().hash)
+            .map_or(0, |entry| entry.cumulative_work)
     }
 
     pub fn is_valid_block(&self, block: &Block) -> bool {
-        let latest_block = self.get_latest_block();
-        let is_valid =
-            block.previous_hash == latest_block.hash && block.hash == block.calculate_hash();
-
-        if !is_valid {
-            eprintln!("Error: Block {} has an invalid hash!", block.index);
+        if let Err(err) = self.validate_block(block) {
+            eprintln!("Error: Block {} is invalid: {}", block.index, err);
             false
         } else {
             true
         }
     }
 
-    pub fn is_valid_chain(&self) -> bool {
-        for i in 1..self.chain.len() {
-            let current_block = &self.chain[i];
-            let previous_block = &self.chain[i - 1];
+    //checks that the block can be appended on top of our latest block.
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        validation::check_header(
+            block,
+            self.get_latest_block(),
+            self.target,
+            self.get_median_time_past(&self.get_latest_block().hash),
+            Utc::now().timestamp() as u64,
+        )?;
+        self.validate_block_transactions(block)
+    }
 
-            if current_block.hash != current_block.calculate_hash() {
-                eprintln!("Error: Block {} has an invalid hash!", current_block.index);
-                return false;
-            }
+    //checks the block's transactions against the unspent outputs of our active chain.
+    fn validate_block_transactions(&self, block: &Block) -> Result<()> {
+        validation::check_transactions(
+            block,
+            self.get_subsidy(block.index),
+            self.get_genesis_hash(),
+            &self.utxos,
+        )?;
+        Ok(())
+    }
 
-            if current_block.previous_hash != previous_block.hash {
-                eprintln!(
-                    "Error: Block {} has an invalid previous hash!",
-                    current_block.index
-                );
-                return false;
-            }
+    pub fn is_valid_chain(&self) -> bool {
+        if let Err(err) = self.validate_chain(&self.chain) {
+            eprintln!("Error: Invalid chain at {}", err);
+            return false;
         }
         true
     }
 
+    //checks a whole chain from genesis against every consensus rule: linkage, hashes, the target each block's
+    //history demands and its proof of work, then the rewards, signatures and spent outputs against a UTXO set rebuilt block by block.
+    //returns the first violation found.
+    pub fn validate_chain(&self, chain: &[Block]) -> Result<(), ValidationError> {
+        let Some(genesis) = chain.first() else {
+            return Err(ValidationError {
+                block_index: 0,
+                tx_index: None,
+                rule: Rule::GenesisMismatch,
+                detail: String::from("chain is empty"),
+            });
+        };
+        if genesis.hash != self.get_genesis_hash() {
+            return Err(ValidationError::block(
+                genesis,
+                Rule::GenesisMismatch,
+                genesis.hash.clone(),
+            ));
+        }
+
+        let mut utxos = UtxoSet::new(self.chain_spec.monetary_policy.coinbase_maturity);
+        utxos.connect_block(genesis);
+        let history_len = self.retarget.history_len();
+        let timestamps: Vec<u64> = chain.iter().map(|block| block.timestamp).collect();
+        let now = Utc::now().timestamp() as u64;
+        for i in 1..chain.len() {
+            let history: Vec<&Block> = chain[i.saturating_sub(history_len)..i].iter().collect();
+            let expected_target = self.next_target_after(&history);
+            validation::check_header(
+                &chain[i],
+                &chain[i - 1],
+                expected_target,
+                validation::median_time_past(&timestamps[..i]),
+                now,
+            )?;
+            validation::check_transactions(
+                &chain[i],
+                self.get_subsidy(chain[i].index),
+                &genesis.hash,
+                &utxos,
+            )?;
+            utxos.connect_block(&chain[i]);
+        }
+        std::result::Result::Ok(())
+    }
+
+    fn tip_changed(&mut self) {
+        self.adjust_difficulty();
+        let tip = self.get_latest_block();
+        self.network.set_best_height(tip.index);
+        let hash = tip.hash.clone();
+        self.tip.send_if_modified(|tip| {
+            let modified = *tip != hash;
+            *tip = hash;
+            modified
+        });
+    }
+
+    //the target for the next block follows from the tip's entry in the block tree.
     pub fn adjust_difficulty(&mut self) {
-        if self.chain.len() < 2 {
+        let tip = &self.get_latest_block().hash;
+        if let Some(entry) = self.block_tree.get(tip) {
+            self.target = entry.next_target;
+        }
+    }
+
+    //target that children of `block` must meet; `block`'s parent has to be in the block tree.
+    //it is recomputed from the branch's own history, so any node holding the same blocks gets the same target.
+    fn calculate_next_target(&self, block: &Block) -> Target {
+        let Some(parent) = self.block_tree.get(&block.previous_hash) else {
             println!("Genesis block detected; no difficulty adjustment needed.");
-            return;
+            return self.target;
+        };
+        let target = parent.next_target;
+
+        let mut history: Vec<&Block> = self
+            .block_tree
+            .ancestors(&block.previous_hash, self.retarget.history_len() - 1)
+            .into_iter()
+            .map(|entry| &entry.block)
+            .collect();
+        history.push(block);
+
+        let next_target = self.next_target_after(&history);
+        if next_target != target {
+            println!(
+                "Difficulty {:.3} -> {:.3} (bits {:08x})",
+                target.difficulty(&self.pow_limit),
+                next_target.difficulty(&self.pow_limit),
+                next_target.to_compact()
+            );
         }
+        next_target
+    }
 
-        let last_block = self.get_latest_block();
-        let second_last_block = self.get_second_last_block();
+    //median timestamp of the block `hash` and those before it on its branch; a child has to be later.
+    fn get_median_time_past(&self, hash: &str) -> u64 {
+        let timestamps: Vec<u64> = self
+            .block_tree
+            .ancestors(hash, validation::MEDIAN_TIME_SPAN)
+            .iter()
+            .map(|entry| entry.block.timestamp)
+            .collect();
+        validation::median_time_past(&timestamps)
+    }
 
-        let last_block_time = DateTime::parse_from_str(&last_block.timestamp, "%+").unwrap();
-        let second_last_block_time =
-            DateTime::parse_from_str(&second_last_block.timestamp, "%+").unwrap();
+    //target for the child of the last block in `history`, which holds the most recent blocks of a branch, oldest first.
+    //every block carries the target it was mined at, so the blocks alone are enough.
+    fn next_target_after(&self, history: &[&Block]) -> Target {
+        let samples: Vec<RetargetSample> = history
+            .iter()
+            .map(|block| RetargetSample {
+                height: block.index,
+                timestamp: block.timestamp,
+                target: Target::from_compact(block.bits).unwrap_or(self.pow_limit),
+            })
+            .collect();
+        self.retarget
+            .next_target(&samples, self.target_time, self.pow_limit)
+    }
 
-        let actual_time = (last_block_time - second_last_block_time).num_seconds() as u64;
+    fn get_latest_block(&self) -> &Block {
+        self.chain.last().unwrap()
+    }
 
-        println!(
-            "Actual block time: {} seconds | Target block time: {} seconds",
-            actual_time, self.target_time
+    pub fn print_chain(&self) {
+        for block in &self.chain {
+            println!("{:?}", block)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{Allocation, TxOutput, Wallet};
+
+    //a chain whose genesis block credits 100 coins to `wallet`.
+    fn funded_chain(wallet: &Wallet) -> Blockchain {
+        let spec = ChainSpec {
+            premine: vec![Allocation {
+                address: wallet.address.clone(),
+                amount: "100".parse().unwrap(),
+            }],
+            ..ChainSpec::default()
+        };
+        Blockchain::new(spec).unwrap()
+    }
+
+    //a block on `parent` paying a `reward` to a fresh address, ten seconds after its parent, with a valid proof of work.
+    fn mine_on(
+        blockchain: &Blockchain,
+        parent: &str,
+        reward: Amount,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let parent = blockchain.block_tree.get(parent).unwrap();
+        let height = parent.block.index + 1;
+        let reward =
+            Transaction::reward(height, vec![TxOutput::new(Wallet::new().address, reward)]);
+        let mut block = Block::new(
+            height,
+            [vec![reward], transactions].concat(),
+            &parent.block.hash,
         );
+        block.timestamp = parent.block.timestamp + 10;
+        block.bits = parent.next_target.to_compact();
+        remine(&mut block);
+        block
+    }
 
-        if actual_time < self.target_time {
-            self.difficulty += 1;
-            println!("Difficulty increased to {}", self.difficulty);
-        } else if actual_time > self.target_time && self.difficulty > 1 {
-            self.difficulty -= 1;
-            println!("Difficulty decreased to {}", self.difficulty);
+    //commits to the block's transactions again and finds a nonce for it.
+    fn remine(block: &mut Block) {
+        block.merkle_root = block.calculate_merkle_root();
+        block.hash = block.calculate_hash();
+        while !block.meets_target() {
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
         }
     }
 
-    fn get_latest_block(&self) -> &Block {
-        self.chain.last().unwrap()
+    fn mine(blockchain: &Blockchain, parent: &str) -> Block {
+        mine_on(blockchain, parent, blockchain.get_subsidy(1), Vec::new())
     }
 
-    fn get_second_last_block(&self) -> &Block {
-        &self.chain[self.chain.len() - 2]
+    fn tip(blockchain: &Blockchain) -> String {
+        blockchain.get_latest_block().hash.clone()
     }
 
-    pub fn print_chain(&self) {
-        for block in &self.chain {
-            println!("{:?}", block)
+    //a transfer of the 100 coins `wallet` got in the genesis block.
+    fn spend_premine(blockchain: &Blockchain, wallet: &Wallet) -> Transaction {
+        let inputs = blockchain
+            .get_spendable_outputs(&wallet.address)
+            .into_iter()
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        let outputs = vec![TxOutput::new(Wallet::new().address, "100".parse().unwrap())];
+        let mut transaction = Transaction::new(wallet.address.clone(), inputs, outputs);
+        wallet
+            .sign_transaction(&mut transaction, blockchain.get_genesis_hash())
+            .unwrap();
+        transaction
+    }
+
+    #[test]
+    fn blocks_batch_stops_at_the_size_limit() {
+        let blockchain = Blockchain::new(ChainSpec::default()).unwrap();
+        let genesis_size = serde_json::to_vec(&blockchain.chain[0]).unwrap().len() + 1;
+
+        let blocks = blockchain.get_blocks_after_locator(&[], SYNC_BATCH_SIZE, genesis_size);
+        assert_eq!(blocks.len(), 1);
+        let blocks = blockchain.get_blocks_after_locator(&[], SYNC_BATCH_SIZE, genesis_size - 1);
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn only_state_independent_transaction_rules_are_penalized() {
+        assert_eq!(
+            Blockchain::misbehavior_for_transaction(Rule::InvalidSignature),
+            Some(Misbehavior::InvalidSignature)
+        );
+        assert_eq!(
+            Blockchain::misbehavior_for_transaction(Rule::InvalidAddress),
+            Some(Misbehavior::InvalidTransaction)
+        );
+        for rule in [Rule::MissingInput, Rule::Nonce, Rule::InsufficientFunds] {
+            assert_eq!(Blockchain::misbehavior_for_transaction(rule), None);
         }
     }
+
+    #[test]
+    fn the_branch_with_the_most_work_becomes_active() {
+        let mut blockchain = funded_chain(&Wallet::new());
+        let genesis = tip(&blockchain);
+        let a1 = mine(&blockchain, &genesis);
+        blockchain.accept_block(a1.clone()).unwrap();
+
+        //as much work as the active chain isn't enough to switch
+        let b1 = mine(&blockchain, &genesis);
+        blockchain.accept_block(b1.clone()).unwrap();
+        assert_eq!(tip(&blockchain), a1.hash);
+
+        let b2 = mine(&blockchain, &b1.hash);
+        blockchain.accept_block(b2.clone()).unwrap();
+        assert_eq!(tip(&blockchain), b2.hash);
+        assert_eq!(blockchain.chain[1].hash, b1.hash);
+        assert!(blockchain.block_tree.contains(&a1.hash));
+    }
+
+    #[test]
+    fn an_invalid_branch_is_dropped_and_the_old_chain_restored() {
+        let mut blockchain = funded_chain(&Wallet::new());
+        let genesis = tip(&blockchain);
+        let a1 = mine(&blockchain, &genesis);
+        blockchain.accept_block(a1.clone()).unwrap();
+        let a2 = mine(&blockchain, &a1.hash);
+        blockchain.accept_block(a2.clone()).unwrap();
+        let b1 = mine(&blockchain, &genesis);
+        blockchain.accept_block(b1.clone()).unwrap();
+        let b2 = mine(&blockchain, &b1.hash);
+        blockchain.accept_block(b2.clone()).unwrap();
+        let utxos = blockchain.utxos.clone();
+
+        let b3 = mine_on(&blockchain, &b2.hash, "1".parse().unwrap(), Vec::new());
+        assert!(blockchain.accept_block(b3.clone()).is_err());
+        assert_eq!(tip(&blockchain), a2.hash);
+        assert_eq!(blockchain.utxos, utxos);
+        assert!(!blockchain.block_tree.contains(&b3.hash));
+        assert!(blockchain.block_tree.contains(&b2.hash));
+    }
+
+    #[test]
+    fn an_aborted_reorganization_moves_to_the_heaviest_valid_block_left() {
+        let mut blockchain = funded_chain(&Wallet::new());
+        let genesis = tip(&blockchain);
+        let a1 = mine(&blockchain, &genesis);
+        blockchain.accept_block(a1.clone()).unwrap();
+
+        //a branch whose blocks were stored without being connected, already heavier than the active chain
+        let b1 = mine(&blockchain, &genesis);
+        blockchain
+            .block_tree
+            .insert(b1.clone(), blockchain.pow_limit)
+            .unwrap();
+        let b2 = mine(&blockchain, &b1.hash);
+        blockchain
+            .block_tree
+            .insert(b2.clone(), blockchain.pow_limit)
+            .unwrap();
+
+        let b3 = mine_on(&blockchain, &b2.hash, "1".parse().unwrap(), Vec::new());
+        assert!(blockchain.accept_block(b3.clone()).is_err());
+        assert_eq!(tip(&blockchain), b2.hash);
+        assert_eq!(blockchain.chain.len(), 3);
+    }
+
+    #[test]
+    fn transactions_of_abandoned_blocks_return_to_the_pool() {
+        let wallet = Wallet::new();
+        let mut blockchain = funded_chain(&wallet);
+        let genesis = tip(&blockchain);
+        let transaction = spend_premine(&blockchain, &wallet);
+
+        let a1 = mine_on(
+            &blockchain,
+            &genesis,
+            blockchain.get_subsidy(1),
+            vec![transaction.clone()],
+        );
+        blockchain.accept_block(a1).unwrap();
+        assert!(blockchain.pending_transactions.is_empty());
+
+        let b1 = mine(&blockchain, &genesis);
+        blockchain.accept_block(b1.clone()).unwrap();
+        let b2 = mine(&blockchain, &b1.hash);
+        blockchain.accept_block(b2.clone()).unwrap();
+        assert_eq!(tip(&blockchain), b2.hash);
+        let pending: Vec<String> = blockchain
+            .pending_transactions
+            .iter()
+            .map(|tx| tx.get_hash())
+            .collect();
+        assert_eq!(pending, [transaction.get_hash()]);
+    }
+
+    #[test]
+    fn tampering_with_a_mined_chain_is_caught() {
+        let wallet = Wallet::new();
+        let mut blockchain = funded_chain(&wallet);
+        let genesis = tip(&blockchain);
+        let transaction = spend_premine(&blockchain, &wallet);
+        let a1 = mine_on(
+            &blockchain,
+            &genesis,
+            blockchain.get_subsidy(1),
+            vec![transaction],
+        );
+        blockchain.accept_block(a1.clone()).unwrap();
+        let a2 = mine(&blockchain, &a1.hash);
+        blockchain.accept_block(a2).unwrap();
+        assert!(blockchain.validate_chain(&blockchain.chain).is_ok());
+
+        let broken_at = |tamper: &dyn Fn(&mut Block)| {
+            let mut chain = blockchain.chain.clone();
+            tamper(&mut chain[1]);
+            let err = blockchain.validate_chain(&chain).unwrap_err();
+            assert_eq!(err.block_index, 1);
+            (err.tx_index, err.rule)
+        };
+
+        //a bogus transaction in place of the block's own, with only the hash recomputed
+        let bogus = Transaction::new(
+            String::from("..."),
+            Vec::new(),
+            vec![TxOutput::new(String::from("..."), "100".parse().unwrap())],
+        );
+        let rehashed = |block: &mut Block| {
+            block.transactions = vec![bogus.clone()];
+            block.hash = block.calculate_hash();
+        };
+        assert_eq!(broken_at(&rehashed), (None, Rule::MerkleRoot));
+        let remined = |block: &mut Block| {
+            block.transactions = vec![bogus.clone()];
+            remine(block);
+        };
+        assert_eq!(broken_at(&remined), (Some(0), Rule::MissingReward));
+
+        let wrong_reward = |block: &mut Block| {
+            block.transactions[0].outputs[0].amount = "1".parse().unwrap();
+            remine(block);
+        };
+        assert_eq!(broken_at(&wrong_reward), (Some(0), Rule::RewardAmount));
+
+        //the transfer pays someone else than its sender signed for
+        let redirected = |block: &mut Block| {
+            block.transactions[1].outputs[0].address = Wallet::new().address;
+            remine(block);
+        };
+        assert_eq!(broken_at(&redirected), (Some(1), Rule::InvalidSignature));
+    }
+
+    #[tokio::test]
+    async fn a_replayed_transaction_is_rejected() {
+        let wallet = Wallet::new();
+        let mut blockchain = funded_chain(&wallet);
+        let genesis = tip(&blockchain);
+        let transaction = spend_premine(&blockchain, &wallet);
+        let receiver = transaction.outputs[0].address.clone();
+        blockchain
+            .add_transaction(transaction.clone())
+            .await
+            .unwrap();
+
+        let a1 = mine_on(
+            &blockchain,
+            &genesis,
+            blockchain.get_subsidy(1),
+            vec![transaction.clone()],
+        );
+        blockchain.accept_block(a1.clone()).unwrap();
+        assert_eq!(blockchain.get_balance(&wallet.address), Amount::ZERO);
+        assert_eq!(blockchain.get_balance(&receiver), "100".parse().unwrap());
+
+        //the same signed transaction again, to the pool and in a block
+        let (rule, _) = blockchain.validate_transaction(&transaction).unwrap_err();
+        assert_eq!(rule, Rule::Nonce);
+        assert!(blockchain
+            .add_transaction(transaction.clone())
+            .await
+            .is_err());
+        let a2 = mine_on(
+            &blockchain,
+            &a1.hash,
+            blockchain.get_subsidy(2),
+            vec![transaction],
+        );
+        let err = blockchain.accept_block(a2).unwrap_err();
+        let err = err.downcast_ref::<ValidationError>().unwrap();
+        assert_eq!((err.tx_index, err.rule), (Some(1), Rule::Nonce));
+    }
 }
@@ -0,0 +1,118 @@
+use super::Amount;
+use super::MonetaryPolicy;
+use super::Retarget;
+use super::Target;
+use super::{Transaction, TxOutput, Wallet};
+use anyhow::{anyhow, Context, Result};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+//the spec every node of a network has to share; it fully determines the genesis block.
+const DEFAULT_CHAIN_SPEC: &str = include_str!("../../chain-spec.json");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainSpec {
+    pub name: String,
+    pub genesis_timestamp: String, //rfc3339
+    pub initial_bits: String, //compact target of the first blocks in hex, also the easiest target ever allowed.
+    pub target_time: u64,     //seconds
+    #[serde(default)]
+    pub retarget: Retarget,
+    #[serde(default)]
+    pub monetary_policy: MonetaryPolicy,
+    pub premine: Vec<Allocation>,
+}
+
+//funds credited to an address by the genesis block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Allocation {
+    pub address: String,
+    pub amount: Amount,
+}
+
+impl ChainSpec {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read chain spec {}", path))?;
+        Self::from_json(&content).with_context(|| format!("Invalid chain spec {}", path))
+    }
+
+    pub fn from_json(content: &str) -> Result<Self> {
+        let spec: ChainSpec = serde_json::from_str(content)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let genesis_time = DateTime::parse_from_rfc3339(&self.genesis_timestamp)
+            .context("genesis_timestamp must be an RFC 3339 timestamp")?;
+        if genesis_time.timestamp() < 0 {
+            return Err(anyhow!("genesis_timestamp must not be before 1970"));
+        }
+        u32::from_str_radix(&self.initial_bits, 16)
+            .context("initial_bits must be a hex encoded compact target")
+            .and_then(Target::from_compact)?;
+        if self.target_time == 0 {
+            return Err(anyhow!("Target time must be greater than 0"));
+        }
+        self.retarget.validate()?;
+        if let Some(allocation) = self
+            .premine
+            .iter()
+            .find(|a| Wallet::decode_address(&a.address).is_err())
+        {
+            return Err(anyhow!(
+                "Premine address {} is not a public key",
+                allocation.address
+            ));
+        }
+        if let Some(allocation) = self.premine.iter().find(|a| a.amount.is_zero()) {
+            return Err(anyhow!(
+                "Premine allocation for {} must be positive",
+                allocation.address
+            ));
+        }
+        let premine = Amount::checked_sum(self.premine.iter().map(|a| a.amount))
+            .context("premine allocations add up to more than an amount can hold")?;
+        self.monetary_policy.validate(premine)?;
+        Ok(())
+    }
+
+    //validated when the spec is loaded.
+    pub fn genesis_unix_timestamp(&self) -> u64 {
+        DateTime::parse_from_rfc3339(&self.genesis_timestamp)
+            .map(|time| time.timestamp() as u64)
+            .expect("chain spec was not validated")
+    }
+
+    //validated when the spec is loaded.
+    pub fn initial_target(&self) -> Target {
+        u32::from_str_radix(&self.initial_bits, 16)
+            .ok()
+            .and_then(|bits| Target::from_compact(bits).ok())
+            .expect("chain spec was not validated")
+    }
+
+    //validated when the spec is loaded.
+    pub fn premine_total(&self) -> Amount {
+        Amount::checked_sum(self.premine.iter().map(|allocation| allocation.amount))
+            .expect("chain spec was not validated")
+    }
+
+    //premine allocations as the outputs of the genesis block's only transaction.
+    pub fn genesis_transactions(&self) -> Vec<Transaction> {
+        let outputs = self
+            .premine
+            .iter()
+            .map(|allocation| TxOutput::new(allocation.address.clone(), allocation.amount))
+            .collect();
+        vec![Transaction::reward(0, outputs)]
+    }
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        Self::from_json(DEFAULT_CHAIN_SPEC).expect("bundled chain-spec.json is invalid")
+    }
+}
@@ -0,0 +1,242 @@
+use super::message::{Envelope, Message};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//frame layout (all integers big-endian):
+//  magic    4 bytes  identifies the network, derived from its genesis hash
+//  length   4 bytes  payload length in bytes
+//  checksum 4 bytes  first 4 bytes of sha256(payload)
+//  payload  `length` bytes
+pub const DEFAULT_MAGIC: [u8; 4] = *b"PLAY";
+pub const HEADER_LEN: usize = 12;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    BadMagic([u8; 4]),
+    TooLarge { size: usize, max: usize },
+    ChecksumMismatch,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::BadMagic(magic) => write!(f, "bad frame magic {}", hex::encode(magic)),
+            FrameError::TooLarge { size, max } => write!(
+                f,
+                "frame of {} bytes exceeds the maximum frame size of {} bytes",
+                size, max
+            ),
+            FrameError::ChecksumMismatch => write!(f, "frame checksum mismatch"),
+            FrameError::Io(err) => write!(f, "i/o error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    magic: [u8; 4],
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            magic: DEFAULT_MAGIC,
+            max_frame_size,
+        }
+    }
+
+    //frames of other networks then fail with a bad magic before their payload is even read.
+    //the tail of the hash is used because its head is the zeros of the proof-of-work.
+    pub fn with_network_id(mut self, genesis_hash: &str) -> Self {
+        if let Ok(bytes) = hex::decode(genesis_hash) {
+            if let Some(tail) = bytes.last_chunk::<4>() {
+                self.magic = *tail;
+            }
+        }
+        self
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    fn checksum(payload: &[u8]) -> [u8; 4] {
+        let digest = Sha256::digest(payload);
+        [digest[0], digest[1], digest[2], digest[3]]
+    }
+
+    //returns Ok(None) when the peer closed the stream cleanly between frames.
+    pub async fn read_frame<R: AsyncRead + Unpin>(
+        &self,
+        reader: &mut R,
+    ) -> Result<Option<Vec<u8>>, FrameError> {
+        let mut header = [0u8; HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+
+        let magic: [u8; 4] = header[0..4].try_into().unwrap();
+        if magic != self.magic {
+            return Err(FrameError::BadMagic(magic));
+        }
+
+        let size = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+        if size > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+
+        let mut payload = vec![0u8; size];
+        reader.read_exact(&mut payload).await?;
+        if header[8..12] != Self::checksum(&payload) {
+            return Err(FrameError::ChecksumMismatch);
+        }
+
+        Ok(Some(payload))
+    }
+
+    pub async fn write_frame<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        payload: &[u8],
+    ) -> Result<(), FrameError> {
+        //refuse to send what a peer with the same limit would refuse to read.
+        if payload.len() > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                size: payload.len(),
+                max: self.max_frame_size,
+            });
+        }
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&self.magic);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&Self::checksum(payload));
+        frame.extend_from_slice(payload);
+        writer.write_all(&frame).await?;
+
+        Ok(())
+    }
+
+    pub async fn write_envelope<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        envelope: &Envelope,
+    ) -> anyhow::Result<()> {
+        let payload = envelope.to_bytes()?;
+        self.write_frame(writer, &payload).await?;
+        Ok(())
+    }
+
+    //for messages that neither expect nor answer anything, like the handshake.
+    pub async fn write_message<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        message: &Message,
+    ) -> anyhow::Result<()> {
+        self.write_envelope(writer, &Envelope::new(message.clone()))
+            .await
+    }
+
+    pub async fn read_envelope<R: AsyncRead + Unpin>(
+        &self,
+        reader: &mut R,
+    ) -> anyhow::Result<Envelope> {
+        let payload = self
+            .read_frame(reader)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("connection closed"))?;
+        Envelope::from_bytes(&payload)
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encode(codec: &FrameCodec, payloads: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for payload in payloads {
+            codec.write_frame(&mut bytes, payload).await.unwrap();
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_back_to_back() {
+        let codec = FrameCodec::default();
+        let bytes = encode(&codec, &[b"first", b"", b"third"]).await;
+
+        let mut reader = bytes.as_slice();
+        for expected in [&b"first"[..], b"", b"third"] {
+            let payload = codec.read_frame(&mut reader).await.unwrap();
+            assert_eq!(payload.as_deref(), Some(expected));
+        }
+        assert!(codec.read_frame(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn frames_of_another_network_are_refused() {
+        let ours = FrameCodec::default().with_network_id(&hex::encode([1u8; 32]));
+        let theirs = FrameCodec::default().with_network_id(&hex::encode([2u8; 32]));
+        let bytes = encode(&theirs, &[b"payload"]).await;
+
+        let err = ours.read_frame(&mut bytes.as_slice()).await.unwrap_err();
+        assert!(matches!(err, FrameError::BadMagic(magic) if magic == [2u8; 4]));
+    }
+
+    #[tokio::test]
+    async fn a_corrupted_payload_fails_the_checksum() {
+        let codec = FrameCodec::default();
+        let mut bytes = encode(&codec, &[b"payload"]).await;
+        bytes[HEADER_LEN] ^= 1;
+
+        let err = codec.read_frame(&mut bytes.as_slice()).await.unwrap_err();
+        assert!(matches!(err, FrameError::ChecksumMismatch));
+
+        //a frame cut short is an error too, not a clean close
+        bytes.pop();
+        let err = codec.read_frame(&mut bytes.as_slice()).await.unwrap_err();
+        assert!(matches!(err, FrameError::Io(_)));
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_both_ways() {
+        let small = FrameCodec::new(4);
+        let err = small
+            .write_frame(&mut Vec::new(), b"12345")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge { size: 5, max: 4 }));
+
+        let bytes = encode(&FrameCodec::default(), &[b"12345"]).await;
+        let err = small.read_frame(&mut bytes.as_slice()).await.unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge { size: 5, max: 4 }));
+
+        let bytes = encode(&small, &[b"1234"]).await;
+        let payload = small.read_frame(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(payload.as_deref(), Some(&b"1234"[..]));
+    }
+}
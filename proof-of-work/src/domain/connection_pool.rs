@@ -0,0 +1,231 @@
+use super::message::{Envelope, Message};
+use super::Network;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{sleep, timeout, Duration};
+
+//messages waiting for a peer's writer; when the queue is full new messages to that peer are dropped instead of blocking the sender.
+pub const WRITE_QUEUE_CAPACITY: usize = 256;
+pub const REQUEST_TIMEOUT_SECS: u64 = 30;
+const MIN_RECONNECT_BACKOFF_SECS: u64 = 1;
+const MAX_RECONNECT_BACKOFF_SECS: u64 = 60;
+//failed connection attempts in a row after which a peer is given up on, about four minutes of backoff.
+const MAX_CONNECT_ATTEMPTS: u32 = 8;
+
+#[derive(Debug)]
+enum Outgoing {
+    Send(Message),
+    Request(Message, oneshot::Sender<Result<Message>>),
+}
+
+//one long-lived outbound connection per peer, each owned by its own task with a write queue and a read loop.
+//connections are opened on first use and re-established with exponential backoff when they drop, until the peer
+//can't be reached MAX_CONNECT_ATTEMPTS times in a row.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionPool {
+    queues: Arc<Mutex<HashMap<String, mpsc::Sender<Outgoing>>>>,
+    next_request_id: Arc<AtomicU64>,
+}
+
+impl ConnectionPool {
+    async fn queue_for(&self, network: &Network, addr: &str) -> mpsc::Sender<Outgoing> {
+        let mut queues = self.queues.lock().await;
+        if let Some(queue) = queues.get(addr) {
+            if !queue.is_closed() {
+                return queue.clone();
+            }
+        }
+
+        let (queue, outgoing) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+        queues.insert(addr.to_string(), queue.clone());
+        tokio::spawn(run_peer(
+            network.clone(),
+            addr.to_string(),
+            outgoing,
+            Arc::clone(&self.queues),
+            Arc::clone(&self.next_request_id),
+        ));
+        queue
+    }
+
+    //queues the message for the peer and returns at once.
+    pub async fn send(&self, network: &Network, addr: &str, message: Message) -> Result<()> {
+        let queue = self.queue_for(network, addr).await;
+        queue
+            .try_send(Outgoing::Send(message))
+            .map_err(|err| queue_error(addr, err))
+    }
+
+    //queues the message and waits for the reply the peer sends back on the same connection.
+    pub async fn request(
+        &self,
+        network: &Network,
+        addr: &str,
+        message: Message,
+    ) -> Result<Message> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let queue = self.queue_for(network, addr).await;
+        queue
+            .try_send(Outgoing::Request(message, reply_tx))
+            .map_err(|err| queue_error(addr, err))?;
+
+        match timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), reply_rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => Err(anyhow!("connection to {} was lost", addr)),
+            Err(_) => Err(anyhow!(
+                "no reply from {} within {} seconds",
+                addr,
+                REQUEST_TIMEOUT_SECS
+            )),
+        }
+    }
+
+    //closes the connection to the peer; its task stops once the queue is dropped.
+    pub async fn disconnect(&self, addr: &str) {
+        self.queues.lock().await.remove(addr);
+    }
+}
+
+fn queue_error<T>(addr: &str, err: TrySendError<T>) -> anyhow::Error {
+    match err {
+        TrySendError::Full(_) => anyhow!("write queue for {} is full", addr),
+        TrySendError::Closed(_) => anyhow!("connection to {} is closed", addr),
+    }
+}
+
+async fn run_peer(
+    network: Network,
+    addr: String,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    queues: Arc<Mutex<HashMap<String, mpsc::Sender<Outgoing>>>>,
+    next_request_id: Arc<AtomicU64>,
+) {
+    let mut backoff = MIN_RECONNECT_BACKOFF_SECS;
+    let mut failed_attempts = 0;
+    loop {
+        match network.connect(&addr).await {
+            Ok((stream, _)) => {
+                println!("Connected to {}", addr);
+                backoff = MIN_RECONNECT_BACKOFF_SECS;
+                failed_attempts = 0;
+                if !serve_peer(&network, &addr, stream, &mut outgoing, &next_request_id).await {
+                    return; //the pool dropped the queue.
+                }
+                network.mark_disconnected(&addr).await;
+            }
+            Err(err) => {
+                eprintln!("Error: Connection to {} failed: {}", addr, err);
+                failed_attempts += 1;
+                if failed_attempts >= MAX_CONNECT_ATTEMPTS {
+                    println!(
+                        "Giving up on {} after {} failed attempts",
+                        addr, failed_attempts
+                    );
+                    network.mark_unreachable(&addr).await;
+                    //the queue is still ours: a new one is only made for a closed queue
+                    queues.lock().await.remove(&addr);
+                    return;
+                }
+            }
+        }
+
+        println!("Reconnecting to {} in {} seconds", addr, backoff);
+        let deadline = sleep(Duration::from_secs(backoff));
+        tokio::pin!(deadline);
+        //while waiting, fail whatever gets queued so callers aren't left hanging
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                next = outgoing.recv() => match next {
+                    None => return,
+                    Some(Outgoing::Request(_, reply)) => {
+                        let _ = reply.send(Err(anyhow!("not connected to {}", addr)));
+                    }
+                    Some(Outgoing::Send(message)) => {
+                        eprintln!("Dropped {} for {}: not connected", message.name(), addr);
+                    }
+                },
+            }
+        }
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF_SECS);
+    }
+}
+
+//pumps the write queue and the read loop of one established connection.
+//returns false once the queue is closed, true when the connection dropped and should be re-established.
+async fn serve_peer(
+    network: &Network,
+    addr: &str,
+    stream: TcpStream,
+    outgoing: &mut mpsc::Receiver<Outgoing>,
+    next_request_id: &AtomicU64,
+) -> bool {
+    let codec = network.codec();
+    let (mut reader, mut writer) = stream.into_split();
+
+    //reading a frame isn't cancel-safe, so it runs on its own task and hands complete envelopes over
+    let (incoming_tx, mut incoming) = mpsc::unbounded_channel();
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let envelope = codec.read_envelope(&mut reader).await;
+            let failed = envelope.is_err();
+            if incoming_tx.send(envelope).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut pending: HashMap<u64, oneshot::Sender<Result<Message>>> = HashMap::new();
+    let queue_open = loop {
+        tokio::select! {
+            next = outgoing.recv() => {
+                let Some(next) = next else {
+                    break false;
+                };
+                let (envelope, reply) = match next {
+                    Outgoing::Send(message) => (Envelope::new(message), None),
+                    Outgoing::Request(message, reply) => {
+                        let id = next_request_id.fetch_add(1, Ordering::Relaxed) + 1;
+                        (Envelope::request(id, message), Some((id, reply)))
+                    }
+                };
+                if let Err(err) = codec.write_envelope(&mut writer, &envelope).await {
+                    eprintln!("Error: Failed to write {} to {}: {}", envelope.message.name(), addr, err);
+                    if let Some((_, reply)) = reply {
+                        let _ = reply.send(Err(err));
+                    }
+                    break true;
+                }
+                if let Some((id, reply)) = reply {
+                    pending.insert(id, reply);
+                }
+            }
+            received = incoming.recv() => {
+                let envelope = match received {
+                    Some(Ok(envelope)) => envelope,
+                    Some(Err(err)) => {
+                        eprintln!("Connection to {} lost: {}", addr, err);
+                        network.report_read_error(addr, &err).await;
+                        break true;
+                    }
+                    None => break true,
+                };
+                network.mark_seen(addr).await;
+                match envelope.reply_to.and_then(|id| pending.remove(&id)) {
+                    Some(reply) => {
+                        let _ = reply.send(Ok(envelope.message));
+                    }
+                    None => network.handle_unsolicited(addr, envelope.message),
+                }
+            }
+        }
+    };
+
+    reader_task.abort();
+    queue_open
+}
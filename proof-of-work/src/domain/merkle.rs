@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+//root of a block without transactions.
+pub const EMPTY_MERKLE_ROOT: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+//leaves and interior nodes are hashed with different prefixes, so a pair of hashes can never pass for a transaction.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+//the sibling hashes from a transaction up to the merkle root, enough to show the transaction is in a block
+//without the rest of its transactions. which side each sibling goes on follows from `index`, and which levels
+//have one at all from `tx_count`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub tx_count: usize,
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    //whether the transaction with `tx_hash` is at `index` in the tree under `merkle_root`. the hash comes from the
+    //caller, who computed it from the transaction itself; a proof with more or fewer siblings than the tree has
+    //levels where the transaction gets paired fails.
+    pub fn verify(&self, tx_hash: &str, merkle_root: &str) -> bool {
+        if self.index >= self.tx_count {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = hash_leaf(tx_hash);
+        let mut position = self.index;
+        let mut level_len = self.tx_count;
+        while level_len > 1 {
+            let sibling = position ^ 1;
+            if sibling < level_len {
+                let Some(sibling_hash) = siblings.next() else {
+                    return false;
+                };
+                hash = if sibling < position {
+                    hash_node(sibling_hash, &hash)
+                } else {
+                    hash_node(&hash, sibling_hash)
+                };
+            }
+            position /= 2;
+            level_len = level_len.div_ceil(2);
+        }
+        siblings.next().is_none() && hash == merkle_root
+    }
+}
+
+//leaves are sha256(0x00 || tx hash) and interior nodes sha256(0x01 || left || right), over raw bytes.
+//a node without a sibling moves up a level unchanged rather than being paired with itself,
+//so repeating the last transaction changes the root.
+pub fn merkle_root(tx_hashes: &[String]) -> String {
+    if tx_hashes.is_empty() {
+        return String::from(EMPTY_MERKLE_ROOT);
+    }
+    let mut level: Vec<String> = tx_hashes.iter().map(|hash| hash_leaf(hash)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+//proof for the transaction at `index`, or None if there is no such transaction.
+pub fn build_proof(tx_hashes: &[String], index: usize) -> Option<MerkleProof> {
+    tx_hashes.get(index)?;
+    let mut siblings = Vec::new();
+    let mut level: Vec<String> = tx_hashes.iter().map(|hash| hash_leaf(hash)).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        if let Some(hash) = level.get(position ^ 1) {
+            siblings.push(hash.clone());
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof {
+        index,
+        tx_count: tx_hashes.len(),
+        siblings,
+    })
+}
+
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn hash_leaf(tx_hash: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(hex::decode(tx_hash).unwrap_or_default());
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_node(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(hex::decode(left).unwrap_or_default());
+    hasher.update(hex::decode(right).unwrap_or_default());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_hashes(count: u8) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("{:x}", Sha256::digest([i])))
+            .collect()
+    }
+
+    #[test]
+    fn proofs_verify_for_every_position() {
+        for count in 1..=7 {
+            let hashes = tx_hashes(count);
+            let root = merkle_root(&hashes);
+            for (index, hash) in hashes.iter().enumerate() {
+                let proof = build_proof(&hashes, index).unwrap();
+                assert!(proof.verify(hash, &root), "{} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn proofs_bind_the_transaction_and_its_position() {
+        let hashes = tx_hashes(5);
+        let root = merkle_root(&hashes);
+        let proof = build_proof(&hashes, 2).unwrap();
+
+        assert!(!proof.verify(&hashes[3], &root));
+        let moved = MerkleProof {
+            index: 3,
+            ..proof.clone()
+        };
+        assert!(!moved.verify(&hashes[2], &root));
+        let mut extra_sibling = proof.clone();
+        extra_sibling.siblings.push(hashes[0].clone());
+        assert!(!extra_sibling.verify(&hashes[2], &root));
+    }
+
+    #[test]
+    fn an_interior_node_is_not_a_valid_leaf() {
+        let hashes = tx_hashes(4);
+        let root = merkle_root(&hashes);
+        //the left half of the tree, offered as if it were a transaction in a two leaf tree
+        let left = hash_node(&hash_leaf(&hashes[0]), &hash_leaf(&hashes[1]));
+        let right = hash_node(&hash_leaf(&hashes[2]), &hash_leaf(&hashes[3]));
+        let forged = MerkleProof {
+            index: 0,
+            tx_count: 2,
+            siblings: vec![right],
+        };
+        assert!(!forged.verify(&left, &root));
+    }
+}
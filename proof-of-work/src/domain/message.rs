@@ -0,0 +1,119 @@
+use super::{Block, Transaction};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+//bumped whenever the shape of a message changes in a way older nodes can't read.
+pub const PROTOCOL_VERSION: u32 = 8;
+//oldest peer version we can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 8;
+pub const USER_AGENT: &str = concat!("/play-chain-pow:", env!("CARGO_PKG_VERSION"), "/");
+
+//every message on the wire is wrapped in an envelope so a receiver can check the version before trusting the payload.
+//connections are long-lived, so a reply carries the id of the message it answers in `reply_to`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    pub id: u64, //0 when the sender doesn't wait for a reply.
+    pub reply_to: Option<u64>,
+    pub message: Message,
+}
+
+impl Envelope {
+    pub fn new(message: Message) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id: 0,
+            reply_to: None,
+            message,
+        }
+    }
+
+    pub fn request(id: u64, message: Message) -> Self {
+        Self {
+            id,
+            ..Self::new(message)
+        }
+    }
+
+    pub fn reply(reply_to: u64, message: Message) -> Self {
+        Self {
+            reply_to: Some(reply_to),
+            ..Self::new(message)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let envelope: Envelope = serde_json::from_slice(bytes)?;
+        if envelope.version < MIN_PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "Unsupported protocol version {} (minimum {})",
+                envelope.version,
+                MIN_PROTOCOL_VERSION
+            ));
+        }
+        Ok(envelope)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum Message {
+    Version(VersionInfo),
+    Verack,
+    GetAddr,
+    Addr(Vec<String>),
+    Tx(Transaction),
+    Block(Block),
+    GetBlocks { locator: Vec<String>, limit: u32 },
+    Blocks(Vec<Block>),
+    Ping(u64),
+    Pong(u64),
+    Reject { reason: String },
+}
+
+//what a node tells a peer about itself when a connection is opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub protocol_version: u32,
+    pub user_agent: String,
+    pub genesis_hash: String,
+    pub best_height: u64,
+    pub listen_addr: Option<String>, //where the sender accepts connections, if it listens at all.
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::GetAddr => "getaddr",
+            Message::Addr(_) => "addr",
+            Message::Tx(_) => "tx",
+            Message::Block(_) => "block",
+            Message::GetBlocks { .. } => "getblocks",
+            Message::Blocks(_) => "blocks",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Reject { .. } => "reject",
+        }
+    }
+
+    pub fn reject(reason: impl Into<String>) -> Self {
+        Message::Reject {
+            reason: reason.into(),
+        }
+    }
+}
+
+//a message the network layer can't answer on its own; it is handed to the node together with a slot for the reply.
+#[derive(Debug)]
+pub struct InboundMessage {
+    pub from: String,
+    pub message: Message,
+    pub reply: oneshot::Sender<Option<Message>>,
+}
@@ -0,0 +1,231 @@
+use super::{Block, BlockHeader, Blockchain, HeaderMidstate, Target};
+use chrono::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+//hashes a worker tries between looks at the cancel flag and the clock.
+const CHECK_INTERVAL: u64 = 4096;
+
+//how each attempt is hashed; `FullHeader` is the straightforward way and only kept as the benchmark baseline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashMethod {
+    FullHeader, //encode the whole header and hash it from scratch
+    Midstate,   //resume from the precomputed state of the header's fixed first chunk
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    //hashes per second.
+    pub fn hashrate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+//searches for a proof of work on worker threads, each scanning its own slice of the nonce space.
+//a search can be cancelled from outside, e.g. as soon as a competing block moves the chain tip.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    //blocks until one worker finds a header meeting the block's target, then returns the solved block.
+    //returns None once `cancel` is set.
+    pub fn mine(&self, mut block: Block, cancel: &AtomicBool) -> Option<(Block, MiningStats)> {
+        let (header, stats) = self.mine_header(block.header(), cancel)?;
+        block.timestamp = header.timestamp;
+        block.nonce = header.nonce;
+        block.hash = block.calculate_hash();
+        Some((block, stats))
+    }
+
+    //like `mine`, for miners that only have the header, such as those working for a node over getwork.
+    pub fn mine_header(
+        &self,
+        header: BlockHeader,
+        cancel: &AtomicBool,
+    ) -> Option<(BlockHeader, MiningStats)> {
+        let (solved, stats) = self.search(header, cancel, HashMethod::Midstate);
+        Some((solved?, stats))
+    }
+
+    //hashes a header no hash can meet for `duration` with the given method.
+    pub fn benchmark(&self, method: HashMethod, duration: Duration) -> MiningStats {
+        let mut block = Block::new(1, Vec::new(), &hex::encode([0u8; 32]));
+        block.bits = 0x0300_0001; //a target of 1
+        let cancel = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(duration);
+                cancel.store(true, Ordering::Relaxed);
+            });
+            self.search(block.header(), &cancel, method).1
+        })
+    }
+
+    fn search(
+        &self,
+        template: BlockHeader,
+        cancel: &AtomicBool,
+        method: HashMethod,
+    ) -> (Option<BlockHeader>, MiningStats) {
+        let started = Instant::now();
+        let Ok(target) = Target::from_compact(template.bits) else {
+            eprintln!(
+                "Error: Block template has an invalid target {:08x}",
+                template.bits
+            );
+            return (
+                None,
+                MiningStats {
+                    hashes: 0,
+                    elapsed: started.elapsed(),
+                },
+            );
+        };
+        //comparing big-endian bytes is comparing the numbers
+        let target = target.to_be_bytes();
+        let found: StdMutex<Option<BlockHeader>> = StdMutex::new(None);
+        let stop = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+
+        let slice = u64::MAX / self.threads as u64;
+        thread::scope(|scope| {
+            for worker in 0..self.threads as u64 {
+                let (found, stop, hashes) = (&found, &stop, &hashes);
+                scope.spawn(move || {
+                    let mut header = template;
+                    let mut midstate = HeaderMidstate::new(&header);
+                    header.nonce = worker * slice;
+                    let end = header.nonce + slice;
+                    let mut tried = 0u64;
+                    while header.nonce < end {
+                        if tried.is_multiple_of(CHECK_INTERVAL) {
+                            if stop.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            //the template's timestamp is the earliest the block may carry
+                            header.timestamp =
+                                (Utc::now().timestamp() as u64).max(template.timestamp);
+                            midstate.set_timestamp(header.timestamp);
+                        }
+                        tried += 1;
+                        let digest = match method {
+                            HashMethod::FullHeader => header.digest(),
+                            HashMethod::Midstate => {
+                                midstate.set_nonce(header.nonce);
+                                midstate.digest()
+                            }
+                        };
+                        if digest <= target {
+                            stop.store(true, Ordering::Relaxed);
+                            found.lock().unwrap().get_or_insert(header);
+                            break;
+                        }
+                        header.nonce += 1;
+                    }
+                    hashes.fetch_add(tried, Ordering::Relaxed);
+                });
+            }
+        });
+
+        let stats = MiningStats {
+            hashes: hashes.into_inner(),
+            elapsed: started.elapsed(),
+        };
+        (found.into_inner().unwrap(), stats)
+    }
+}
+
+//compares the two hash methods on this machine and prints the hashrates.
+pub fn bench_mining(miner: &Miner, duration: Duration) {
+    println!(
+        "Benchmarking mining on {} threads, {} seconds per method",
+        miner.threads(),
+        duration.as_secs()
+    );
+    let baseline = miner.benchmark(HashMethod::FullHeader, duration);
+    println!("Full header: {:.1} kH/s", baseline.hashrate() / 1000.0);
+    let midstate = miner.benchmark(HashMethod::Midstate, duration);
+    println!("Midstate:    {:.1} kH/s", midstate.hashrate() / 1000.0);
+    println!(
+        "Speedup:     {:.2}x",
+        midstate.hashrate() / baseline.hashrate().max(f64::EPSILON)
+    );
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |threads| threads.get()))
+    }
+}
+
+//mines one block on top of the current tip without holding the blockchain lock, so peers' blocks keep coming in.
+//the search is abandoned when the tip moves; returns the block if it was mined and connected.
+pub async fn mine_next_block(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    miner: &Miner,
+    miner_address: &str,
+) -> Option<Block> {
+    let (template, mut tip) = {
+        let blockchain = blockchain.lock().await;
+        let template = blockchain.create_block_template(miner_address.to_string())?;
+        (template, blockchain.subscribe_tip())
+    };
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let watcher = tokio::spawn({
+        let cancel = Arc::clone(&cancel);
+        async move {
+            if tip.changed().await.is_ok() {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+
+    let worker_miner = miner.clone();
+    let worker_cancel = Arc::clone(&cancel);
+    let mined = tokio::task::spawn_blocking(move || worker_miner.mine(template, &worker_cancel))
+        .await
+        .ok()
+        .flatten();
+    watcher.abort();
+
+    let Some((block, stats)) = mined else {
+        println!("Mining cancelled: the chain tip changed");
+        return None;
+    };
+    println!(
+        "Block mined: {} with nonce: {} ({:.1} kH/s on {} threads)",
+        block.hash,
+        block.nonce,
+        stats.hashrate() / 1000.0,
+        miner.threads()
+    );
+
+    match blockchain.lock().await.submit_block(block.clone()).await {
+        Ok(()) => Some(block),
+        Err(err) => {
+            eprintln!("Error: Mined block {} was rejected: {}", block.index, err);
+            None
+        }
+    }
+}
@@ -1,13 +1,45 @@
 //declare submodules
+mod amount;
+mod ban_list;
 mod block;
+mod block_assembler;
+mod block_header;
+mod block_tree;
 mod blockchain;
+mod chain_spec;
+mod codec;
+mod connection_pool;
+mod merkle;
+mod message;
+pub mod miner;
+mod monetary_policy;
 mod network;
+mod peer;
+mod retarget;
+pub mod sync;
+mod target;
 mod transaction;
+mod utxo_set;
+mod validation;
 mod wallet;
+pub mod work_server;
 
 //re-export the mods for easier access
-pub use block::Block;
+pub use amount::{Amount, COIN};
+pub use ban_list::{BanEntry, Misbehavior};
+pub use block::{Block, MAX_BLOCK_SIZE};
+pub use block_header::{BlockHeader, HeaderMidstate, BLOCK_VERSION, HEADER_SIZE};
 pub use blockchain::Blockchain;
+pub use chain_spec::{Allocation, ChainSpec};
+pub use merkle::MerkleProof;
+pub use message::{InboundMessage, Message, VersionInfo};
+pub use monetary_policy::MonetaryPolicy;
 pub use network::Network;
-pub use transaction::Transaction;
+pub use peer::{ConnectionState, Direction, PeerInfo};
+pub use retarget::{Retarget, RetargetSample};
+pub use target::Target;
+pub use transaction::{OutPoint, Transaction, TxOutput};
+pub use utxo_set::{Utxo, UtxoSet};
+pub use validation::{Rule, ValidationError};
 pub use wallet::Wallet;
+pub use work_server::WorkServer;
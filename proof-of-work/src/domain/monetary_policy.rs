@@ -0,0 +1,162 @@
+use super::{Amount, COIN};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+//after this many halvings the subsidy is zero, whatever it started at.
+const MAX_HALVINGS: u64 = 64;
+//longest coinbase_maturity a spec may ask for, about a year of blocks at a 10 second target time.
+pub const MAX_COINBASE_MATURITY: u64 = 3_000_000;
+
+//how coins come into existence: every block after genesis pays its miner a subsidy that halves every
+//`halving_interval` blocks, until `max_supply` coins exist, premine included. a reward can only be spent
+//`coinbase_maturity` blocks after the block paying it, so a reorganization can't undo coins that were passed on.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MonetaryPolicy {
+    pub initial_subsidy: Amount,
+    pub halving_interval: u64, //blocks
+    pub max_supply: Amount,
+    pub coinbase_maturity: u64, //blocks
+}
+
+impl MonetaryPolicy {
+    pub fn validate(&self, premine: Amount) -> Result<()> {
+        if self.halving_interval == 0 {
+            return Err(anyhow!("halving_interval must be greater than 0"));
+        }
+        if self.coinbase_maturity > MAX_COINBASE_MATURITY {
+            return Err(anyhow!(
+                "coinbase_maturity must be at most {} blocks",
+                MAX_COINBASE_MATURITY
+            ));
+        }
+        if self.max_supply < premine {
+            return Err(anyhow!(
+                "max_supply must be at least the premine of {}",
+                premine
+            ));
+        }
+        Ok(())
+    }
+
+    //subsidy of the block at `height`; the one that reaches max_supply gets only what is left.
+    pub fn subsidy(&self, height: u64, premine: Amount) -> Amount {
+        if height == 0 {
+            return Amount::ZERO; //genesis only carries the premine
+        }
+        let left = self
+            .max_supply
+            .saturating_sub(self.supply(height - 1, premine));
+        self.scheduled_subsidy(height).min(left)
+    }
+
+    //coins in existence once the block at `height` is connected: the premine plus every subsidy so far.
+    //never more than max_supply, which is at least the premine.
+    pub fn supply(&self, height: u64, premine: Amount) -> Amount {
+        let issuable = self.max_supply.saturating_sub(premine);
+        let issued = self
+            .scheduled_issuance(height)
+            .min(issuable.units() as u128);
+        Amount::from_units(premine.units().saturating_add(issued as u64))
+    }
+
+    //each halving drops the lowest bit, so the subsidy ends up at zero units.
+    fn scheduled_subsidy(&self, height: u64) -> Amount {
+        let halvings = (height - 1) / self.halving_interval;
+        if halvings >= MAX_HALVINGS {
+            return Amount::ZERO;
+        }
+        Amount::from_units(self.initial_subsidy.units() >> halvings)
+    }
+
+    //base units paid to blocks 1 to `height` ignoring max_supply, summed one halving era at a time. wide enough
+    //not to overflow before max_supply caps it.
+    fn scheduled_issuance(&self, height: u64) -> u128 {
+        let mut issued = 0u128;
+        let mut subsidy = self.initial_subsidy.units() as u128;
+        let mut era_start = 1u64;
+        for _ in 0..MAX_HALVINGS {
+            if era_start > height {
+                break;
+            }
+            let era_end = era_start
+                .saturating_add(self.halving_interval - 1)
+                .min(height);
+            issued = issued.saturating_add(subsidy * (era_end - era_start + 1) as u128);
+            era_start = era_end.saturating_add(1);
+            subsidy >>= 1;
+        }
+        issued
+    }
+}
+
+impl Default for MonetaryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_subsidy: Amount::from_units(COIN / 2),
+            halving_interval: 210_000,
+            max_supply: Amount::from_units(21_000_000 * COIN),
+            coinbase_maturity: 100,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_supply: u64) -> MonetaryPolicy {
+        MonetaryPolicy {
+            initial_subsidy: Amount::from_units(50 * COIN),
+            halving_interval: 10,
+            max_supply: Amount::from_units(max_supply * COIN),
+            coinbase_maturity: 100,
+        }
+    }
+
+    #[test]
+    fn subsidy_halves_every_interval() {
+        let policy = policy(21_000_000);
+        let premine = Amount::ZERO;
+        assert_eq!(policy.subsidy(0, premine), Amount::ZERO);
+        assert_eq!(policy.subsidy(1, premine), Amount::from_units(50 * COIN));
+        assert_eq!(policy.subsidy(10, premine), Amount::from_units(50 * COIN));
+        assert_eq!(policy.subsidy(11, premine), Amount::from_units(25 * COIN));
+        assert_eq!(
+            policy.subsidy(21, premine),
+            Amount::from_units(25 * COIN / 2)
+        );
+        assert_eq!(policy.subsidy(10 * 64 + 1, premine), Amount::ZERO);
+        assert_eq!(policy.supply(20, premine), Amount::from_units(750 * COIN));
+    }
+
+    #[test]
+    fn issuance_stops_at_max_supply() {
+        //100 premined, then 50 per block: block 17 takes the supply to 950 and block 18 gets only the 40 left
+        let policy = MonetaryPolicy {
+            halving_interval: 1_000,
+            ..policy(990)
+        };
+        let premine = Amount::from_units(100 * COIN);
+        assert_eq!(policy.supply(17, premine), Amount::from_units(950 * COIN));
+        assert_eq!(policy.subsidy(18, premine), Amount::from_units(40 * COIN));
+        assert_eq!(policy.subsidy(19, premine), Amount::ZERO);
+        assert_eq!(policy.supply(u64::MAX, premine), policy.max_supply);
+    }
+
+    #[test]
+    fn validate_rejects_unusable_policies() {
+        let premine = Amount::from_units(100 * COIN);
+        assert!(policy(1_000).validate(premine).is_ok());
+        assert!(policy(99).validate(premine).is_err());
+        let no_halving = MonetaryPolicy {
+            halving_interval: 0,
+            ..policy(1_000)
+        };
+        assert!(no_halving.validate(premine).is_err());
+        let endless_maturity = MonetaryPolicy {
+            coinbase_maturity: u64::MAX,
+            ..policy(1_000)
+        };
+        assert!(endless_maturity.validate(premine).is_err());
+    }
+}
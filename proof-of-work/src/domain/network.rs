@@ -1,24 +1,98 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use super::ban_list::{host_of, BanEntry, BanList, Misbehavior};
+use super::codec::{FrameCodec, FrameError};
+use super::connection_pool::ConnectionPool;
+use super::message::{
+    Envelope, InboundMessage, Message, VersionInfo, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    USER_AGENT,
+};
+use super::peer::{
+    parse_peer_addr, ConnectionState, Direction, PeerBook, PeerInfo, MAX_ADDR_PER_MESSAGE,
+    MAX_OUTBOUND_PEERS,
+};
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use tokio::sync::oneshot;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{timeout, Duration};
+
+//how long connecting and handshaking with a peer may take, in either direction.
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+//inbound connections that may be handshaking at once; more are closed right away.
+pub const MAX_PENDING_INBOUND: usize = 32;
 
 #[derive(Debug, Clone)]
 pub struct Network {
-    peers: Arc<Mutex<HashSet<String>>>,
+    peers: Arc<Mutex<PeerBook>>,
+    inbound: Option<mpsc::UnboundedSender<InboundMessage>>, //where chain-level messages are handed over to the node.
+    codec: FrameCodec,
+    network_id: String, //genesis hash of the chain we are on.
+    best_height: Arc<AtomicU64>,
+    listen_addr: Option<String>,
+    pool: ConnectionPool,
+    bans: Arc<Mutex<BanList>>,
+    handshake_slots: Arc<Semaphore>, //one per inbound connection that hasn't finished its handshake.
+}
+
+//what a broken frame says about the peer that sent it; a foreign magic is just a node of another network.
+fn misbehavior_for_frame_error(err: &FrameError) -> Option<Misbehavior> {
+    match err {
+        FrameError::TooLarge { .. } => Some(Misbehavior::OversizedFrame),
+        FrameError::ChecksumMismatch => Some(Misbehavior::BadFrame),
+        FrameError::BadMagic(_) | FrameError::Io(_) => None,
+    }
 }
 
 impl Network {
     pub fn new() -> Self {
         Self {
-            peers: Arc::new(Mutex::new(HashSet::new())),
+            peers: Arc::new(Mutex::new(PeerBook::default())),
+            inbound: None,
+            codec: FrameCodec::default(),
+            network_id: String::new(),
+            best_height: Arc::new(AtomicU64::new(0)),
+            listen_addr: None,
+            pool: ConnectionPool::default(),
+            bans: Arc::new(Mutex::new(BanList::default())),
+            handshake_slots: Arc::new(Semaphore::new(MAX_PENDING_INBOUND)),
         }
     }
 
+    pub fn set_ban_policy(&mut self, threshold: u32, duration_secs: i64) {
+        self.bans = Arc::new(Mutex::new(BanList::new(threshold, duration_secs)));
+    }
+
+    pub fn codec(&self) -> FrameCodec {
+        self.codec
+    }
+
+    //the address we accept connections on; announced in handshakes and never dialed ourselves.
+    pub async fn set_listen_addr(&mut self, listen_addr: String) {
+        self.peers.lock().await.set_own_addr(listen_addr.clone());
+        self.listen_addr = Some(listen_addr);
+    }
+
+    //kept up to date by the blockchain so handshakes advertise our current tip.
+    pub fn set_best_height(&self, best_height: u64) {
+        self.best_height.store(best_height, Ordering::Relaxed);
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.codec = FrameCodec::new(max_frame_size).with_network_id(&self.network_id);
+    }
+
+    pub fn set_network_id(&mut self, genesis_hash: &str) {
+        self.network_id = genesis_hash.to_string();
+        self.codec = self.codec.with_network_id(genesis_hash);
+    }
+
+    pub fn set_inbound(&mut self, inbound: mpsc::UnboundedSender<InboundMessage>) {
+        self.inbound = Some(inbound);
+    }
+
     pub async fn start_listening(&self, bind_addr: &str) -> tokio::task::JoinHandle<()> {
         let listener: TcpListener = TcpListener::bind(bind_addr)
             .await
@@ -26,77 +100,489 @@ impl Network {
 
         println!("Listening for peers on {}", bind_addr);
 
-        let peers = Arc::clone(&self.peers);
+        let network = self.clone();
         tokio::spawn(async move {
             loop {
                 if let Ok((stream, addr)) = listener.accept().await {
-                    //the lock is held only for the duration of inserting the new peer into the peers set, and then the lock is released.
-                    let mut peers = {
-                        let locked_peers = peers.lock().await;
-                        locked_peers.clone()
+                    if network.is_banned(&addr.to_string()).await {
+                        println!("Refused connection from banned peer {}", addr);
+                        continue;
+                    }
+                    let Ok(slot) = Arc::clone(&network.handshake_slots).try_acquire_owned() else {
+                        println!(
+                            "Refused connection from {}: too many pending handshakes",
+                            addr
+                        );
+                        continue;
                     };
-                    peers.insert(addr.to_string());
                     println!("New connection from {}", addr);
 
-                    Network::handle_connection(stream).await;
+                    let network = network.clone();
+                    tokio::spawn(async move {
+                        network
+                            .handle_connection(stream, addr.to_string(), slot)
+                            .await;
+                    });
                 }
             }
         })
     }
 
-    pub async fn handle_connection(mut stream: TcpStream) {
-        let mut buffer = vec![0; 1024];
+    //`slot` is held until the handshake is over.
+    pub async fn handle_connection(
+        &self,
+        mut stream: TcpStream,
+        from: String,
+        slot: OwnedSemaphorePermit,
+    ) {
+        let handshake = timeout(
+            Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+            self.inbound_handshake(&mut stream),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow!(
+                "no handshake within {} seconds",
+                HANDSHAKE_TIMEOUT_SECS
+            ))
+        });
+        drop(slot);
+        match handshake {
+            Ok(remote) => {
+                println!(
+                    "Handshake with {} completed: {} at height {}",
+                    from, remote.user_agent, remote.best_height
+                );
+                //the inbound peer is recorded for as long as its connection lives
+                self.peers
+                    .lock()
+                    .await
+                    .mark_connected(&from, Direction::Inbound, &remote);
+            }
+            Err(err) => {
+                eprintln!("Handshake with {} failed: {}", from, err);
+                if let Some(misbehavior) = err
+                    .downcast_ref::<FrameError>()
+                    .and_then(misbehavior_for_frame_error)
+                {
+                    self.report_misbehavior(&from, misbehavior).await;
+                }
+                return;
+            }
+        }
+
+        self.serve_connection(&mut stream, &from).await;
+        self.peers.lock().await.mark_disconnected(&from);
+    }
+
+    async fn serve_connection(&self, stream: &mut TcpStream, from: &str) {
         loop {
-            buffer.fill(0);
-            match stream.read(&mut buffer).await {
-                Ok(0) => {
+            let payload = match self.codec.read_frame(stream).await {
+                Ok(Some(payload)) => payload,
+                Ok(None) => {
                     println!("Connection closed by client");
                     break;
                 }
-                Ok(n) => {
-                    let message = String::from_utf8_lossy(&buffer[..n]);
-                    println!("Received: {}", message);
-
-                    //TODO: replace this with custom handling logic.
-                    if let Err(err) = stream.write_all(message.as_bytes()).await {
-                        eprintln!("Failed to write to stream: {}", err);
-                        break;
-                    }
+                Err(FrameError::Io(err)) => {
+                    eprintln!("Connection error: {}", err);
+                    break;
                 }
                 Err(err) => {
-                    eprintln!("Connection error: {}", err);
+                    //the stream can't be resynchronised after a bad frame, so tell the peer why and drop it.
+                    eprintln!("Invalid frame from {}: {}", from, err);
+                    let _ = self
+                        .codec
+                        .write_message(stream, &Message::reject(err.to_string()))
+                        .await;
+                    if let Some(misbehavior) = misbehavior_for_frame_error(&err) {
+                        self.report_misbehavior(from, misbehavior).await;
+                    }
                     break;
                 }
+            };
+
+            self.mark_seen(from).await;
+            let reply = match Envelope::from_bytes(&payload) {
+                Ok(envelope) => {
+                    println!("Received {} from {}", envelope.message.name(), from);
+                    self.dispatch(from, envelope.message)
+                        .await
+                        .map(|reply| Envelope::reply(envelope.id, reply))
+                }
+                Err(err) => {
+                    eprintln!("Malformed message from {}: {}", from, err);
+                    self.report_misbehavior(from, Misbehavior::MalformedMessage)
+                        .await;
+                    Some(Envelope::new(Message::reject(format!(
+                        "malformed message: {}",
+                        err
+                    ))))
+                }
+            };
+
+            //handling the message may have got the peer banned
+            if self.is_banned(from).await {
+                println!("Disconnecting banned peer {}", from);
+                break;
+            }
+
+            let Some(reply) = reply else {
+                continue;
+            };
+            if let Err(err) = self.codec.write_envelope(stream, &reply).await {
+                eprintln!(
+                    "Failed to write {} reply to {}: {}",
+                    reply.message.name(),
+                    from,
+                    err
+                );
+                break;
             }
         }
     }
 
-    pub async fn send_message(&self, addr: &str, message: &str) -> tokio::io::Result<()> {
-        let mut stream: TcpStream = TcpStream::connect(addr).await?;
-        stream.write_all(message.as_bytes()).await?;
+    fn local_version(&self) -> VersionInfo {
+        VersionInfo {
+            protocol_version: PROTOCOL_VERSION,
+            user_agent: USER_AGENT.to_string(),
+            genesis_hash: self.network_id.clone(),
+            best_height: self.best_height.load(Ordering::Relaxed),
+            listen_addr: self.listen_addr.clone(),
+        }
+    }
 
-        let mut buffer = vec![0; 1024];
-        let n = stream.read(&mut buffer).await?;
+    fn check_version(&self, remote: &VersionInfo) -> Result<()> {
+        if remote.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "incompatible protocol version {} (minimum {})",
+                remote.protocol_version,
+                MIN_PROTOCOL_VERSION
+            ));
+        }
+        if remote.genesis_hash != self.network_id {
+            return Err(anyhow!(
+                "different chain, genesis {} instead of {}",
+                remote.genesis_hash,
+                self.network_id
+            ));
+        }
+        Ok(())
+    }
+
+    async fn read_message(&self, stream: &mut TcpStream) -> Result<Message> {
+        Ok(self.codec.read_envelope(stream).await?.message)
+    }
+
+    //we opened the connection: send our version, check theirs, and exchange veracks.
+    async fn outbound_handshake(&self, stream: &mut TcpStream) -> Result<VersionInfo> {
+        self.codec
+            .write_message(stream, &Message::Version(self.local_version()))
+            .await?;
+
+        let remote = match self.read_message(stream).await? {
+            Message::Version(remote) => remote,
+            Message::Reject { reason } => return Err(anyhow!("rejected: {}", reason)),
+            other => return Err(anyhow!("expected version, got {}", other.name())),
+        };
+        if let Err(err) = self.check_version(&remote) {
+            let _ = self
+                .codec
+                .write_message(stream, &Message::reject(err.to_string()))
+                .await;
+            return Err(err);
+        }
+        if !matches!(self.read_message(stream).await?, Message::Verack) {
+            return Err(anyhow!("expected verack"));
+        }
+        self.codec.write_message(stream, &Message::Verack).await?;
+
+        Ok(remote)
+    }
+
+    //the peer opened the connection: nothing else is accepted until it has sent a compatible version.
+    async fn inbound_handshake(&self, stream: &mut TcpStream) -> Result<VersionInfo> {
+        let remote = match self.read_message(stream).await? {
+            Message::Version(remote) => remote,
+            other => {
+                let reason = format!("expected version, got {}", other.name());
+                self.report_misbehavior(
+                    &stream.peer_addr()?.to_string(),
+                    Misbehavior::UnexpectedMessage,
+                )
+                .await;
+                let _ = self
+                    .codec
+                    .write_message(stream, &Message::reject(reason.clone()))
+                    .await;
+                return Err(anyhow!(reason));
+            }
+        };
+        if let Err(err) = self.check_version(&remote) {
+            let _ = self
+                .codec
+                .write_message(stream, &Message::reject(err.to_string()))
+                .await;
+            return Err(err);
+        }
+
+        self.codec
+            .write_message(stream, &Message::Version(self.local_version()))
+            .await?;
+        self.codec.write_message(stream, &Message::Verack).await?;
+        if !matches!(self.read_message(stream).await?, Message::Verack) {
+            return Err(anyhow!("expected verack"));
+        }
+
+        Ok(remote)
+    }
+
+    //opens a connection to a peer and handshakes, keeping its entry in the peer book up to date.
+    pub async fn connect(&self, addr: &str) -> Result<(TcpStream, VersionInfo)> {
+        if self.is_banned(addr).await {
+            return Err(anyhow!("peer {} is banned", addr));
+        }
+
+        let result = timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), async {
+            let mut stream: TcpStream = TcpStream::connect(addr).await?;
+            let remote = self.outbound_handshake(&mut stream).await?;
+            Ok((stream, remote))
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow!(
+                "no handshake within {} seconds",
+                HANDSHAKE_TIMEOUT_SECS
+            ))
+        });
+
+        let mut peers = self.peers.lock().await;
+        match result {
+            Ok((stream, remote)) => {
+                peers.mark_connected(addr, Direction::Outbound, &remote);
+                Ok((stream, remote))
+            }
+            Err(err) => {
+                peers.mark_disconnected(addr);
+                Err(err)
+            }
+        }
+    }
+
+    //connects to a peer only to learn its version and best height. the connection is closed right away, so the
+    //peer is left connected only if the pool already had a connection to it.
+    pub async fn handshake(&self, addr: &str) -> Result<VersionInfo> {
+        let was_connected = self
+            .peers
+            .lock()
+            .await
+            .get(addr)
+            .is_some_and(|peer| peer.state == ConnectionState::Connected);
+        let (mut stream, remote) = self.connect(addr).await?;
+        let _ = stream.shutdown().await;
+        if !was_connected {
+            self.mark_disconnected(addr).await;
+        }
+        Ok(remote)
+    }
+
+    pub async fn mark_seen(&self, addr: &str) {
+        self.peers.lock().await.mark_seen(addr);
+    }
+
+    pub async fn mark_disconnected(&self, addr: &str) {
+        self.peers.lock().await.mark_disconnected(addr);
+    }
+
+    pub async fn mark_unreachable(&self, addr: &str) {
+        self.peers.lock().await.mark_unreachable(addr);
+    }
+
+    //a read failure on an outbound connection is only the peer's fault if it sent garbage.
+    pub async fn report_read_error(&self, addr: &str, err: &anyhow::Error) {
+        let misbehavior = match err.downcast_ref::<FrameError>() {
+            Some(frame_err) => misbehavior_for_frame_error(frame_err),
+            None if err.is::<serde_json::Error>() => Some(Misbehavior::MalformedMessage),
+            None => None,
+        };
+        if let Some(misbehavior) = misbehavior {
+            self.report_misbehavior(addr, misbehavior).await;
+        }
+    }
+
+    //messages an outbound peer sends us without us waiting for them, typically rejections of what we relayed.
+    pub fn handle_unsolicited(&self, addr: &str, message: Message) {
+        match message {
+            Message::Reject { reason } => {
+                eprintln!("Peer {} rejected our message: {}", addr, reason)
+            }
+            other => eprintln!("Unexpected {} from {}", other.name(), addr),
+        }
+    }
+
+    //raises the peer's ban score; once it crosses the threshold its host is banned and our connections to it are dropped.
+    pub async fn report_misbehavior(&self, addr: &str, misbehavior: Misbehavior) {
+        let Some(ban) = self.bans.lock().await.record(addr, misbehavior) else {
+            return;
+        };
         println!(
-            "Acknowledgment received: {}",
-            String::from_utf8_lossy(&buffer[..n])
+            "Banned {} until {} ({})",
+            ban.host,
+            ban.banned_until.to_rfc3339(),
+            ban.reason
         );
 
-        Ok(())
+        //inbound connections notice the ban after the message they are handling
+        for peer in self.get_peer_infos().await {
+            if host_of(&peer.addr) == ban.host && peer.direction == Direction::Outbound {
+                self.pool.disconnect(&peer.addr).await;
+            }
+        }
+    }
+
+    pub async fn is_banned(&self, addr: &str) -> bool {
+        self.bans.lock().await.is_banned(addr)
+    }
+
+    pub async fn get_bans(&self) -> Vec<BanEntry> {
+        self.bans.lock().await.list()
+    }
+
+    pub async fn unban(&self, host: &str) -> bool {
+        self.bans.lock().await.unban(host)
+    }
+
+    pub async fn clear_bans(&self) {
+        self.bans.lock().await.clear();
+    }
+
+    pub async fn get_peer_infos(&self) -> Vec<PeerInfo> {
+        self.peers.lock().await.all()
+    }
+
+    //asks every known peer for the addresses it knows, so a node started with a single seed finds the rest of the network.
+    pub async fn discover_peers(&self) {
+        for peer in self.get_peers().await {
+            match self.request(&peer, &Message::GetAddr).await {
+                Ok(Message::Addr(addrs)) => {
+                    let learned = self.add_addresses(addrs).await;
+                    if learned > 0 {
+                        println!("Learned {} new peer addresses from {}", learned, peer);
+                    }
+                }
+                Ok(other) => {
+                    eprintln!("Unexpected {} reply to getaddr from {}", other.name(), peer)
+                }
+                Err(err) => eprintln!("Error: Peer discovery with {} failed: {}", peer, err),
+            }
+        }
+    }
+
+    async fn add_addresses(&self, addrs: Vec<String>) -> usize {
+        let mut peers = self.peers.lock().await;
+        addrs
+            .into_iter()
+            .take(MAX_ADDR_PER_MESSAGE)
+            .filter(|addr| peers.add_address(addr.clone()))
+            .count()
+    }
+
+    //protocol-level messages are answered here, everything touching the chain is forwarded to the node.
+    async fn dispatch(&self, from: &str, message: Message) -> Option<Message> {
+        match message {
+            Message::Version(_) | Message::Verack => {
+                self.report_misbehavior(from, Misbehavior::UnexpectedMessage)
+                    .await;
+                Some(Message::reject("handshake already completed"))
+            }
+            Message::GetAddr => Some(Message::Addr(
+                self.peers.lock().await.addresses_for_gossip(),
+            )),
+            Message::Addr(_) => {
+                //addresses are only taken as the reply to our getaddr, which comes back on our own connection
+                self.report_misbehavior(from, Misbehavior::UnexpectedMessage)
+                    .await;
+                Some(Message::reject("addr was not asked for"))
+            }
+            Message::Ping(nonce) => Some(Message::Pong(nonce)),
+            Message::Pong(nonce) => {
+                println!("Pong {} from {}", nonce, from);
+                None
+            }
+            Message::Reject { reason } => {
+                eprintln!("Peer {} rejected our message: {}", from, reason);
+                None
+            }
+            Message::Tx(_) | Message::Block(_) | Message::GetBlocks { .. } | Message::Blocks(_) => {
+                self.forward(from, message).await
+            }
+        }
+    }
+
+    async fn forward(&self, from: &str, message: Message) -> Option<Message> {
+        let Some(inbound) = &self.inbound else {
+            return Some(Message::reject("node is not accepting chain messages"));
+        };
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let inbound_message = InboundMessage {
+            from: from.to_string(),
+            message,
+            reply: reply_tx,
+        };
+        if inbound.send(inbound_message).is_err() {
+            return Some(Message::reject("node is shutting down"));
+        }
+        reply_rx.await.unwrap_or(None)
+    }
+
+    //fire-and-forget: queue the message on the peer's connection.
+    pub async fn send_message(&self, addr: &str, message: &Message) -> Result<()> {
+        self.pool.send(self, addr, message.clone()).await
+    }
+
+    //send a message and wait for the peer's reply.
+    pub async fn request(&self, addr: &str, message: &Message) -> Result<Message> {
+        self.pool.request(self, addr, message.clone()).await
+    }
+
+    //queue the message for every known peer; neither a slow nor an unreachable peer holds up the caller.
+    pub async fn broadcast(&self, message: &Message) {
+        for peer in self.get_peers().await {
+            if let Err(err) = self.send_message(&peer, message).await {
+                eprintln!(
+                    "Error:Failed to send {} to {}: {}",
+                    message.name(),
+                    peer,
+                    err
+                );
+            }
+        }
     }
 
     pub async fn add_peer(&self, addr: String) {
-        self.peers.lock().await.insert(addr);
+        self.peers.lock().await.add_address(addr);
     }
 
     pub async fn add_peers(&mut self, addrs: Vec<String>) {
         let mut peers = self.peers.lock().await;
         for addr in addrs {
-            peers.insert(addr);
+            if parse_peer_addr(&addr).is_none() {
+                eprintln!("Error: Ignoring peer {}: not an ip address and port", addr);
+                continue;
+            }
+            peers.add_address(addr);
         }
     }
 
+    //the peers we keep outbound connections to: at most MAX_OUTBOUND_PEERS of the address book, the rest are only
+    //candidates. inbound connections are tracked but not listed here.
     pub async fn get_peers(&self) -> Vec<String> {
-        self.peers.lock().await.iter().cloned().collect()
+        let addrs = self.peers.lock().await.outbound_addresses();
+        let mut bans = self.bans.lock().await;
+        addrs
+            .into_iter()
+            .filter(|addr| !bans.is_banned(addr))
+            .take(MAX_OUTBOUND_PEERS)
+            .collect()
     }
 }
@@ -0,0 +1,290 @@
+use super::VersionInfo;
+use chrono::prelude::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+//most addresses shared in a single addr message.
+pub const MAX_ADDR_PER_MESSAGE: usize = 100;
+//most dialable addresses the book holds; gossip beyond that is ignored.
+pub const MAX_ADDRESS_BOOK_SIZE: usize = 1000;
+//most peers we dial at once; the rest of the address book are candidates for when one of them fails.
+pub const MAX_OUTBOUND_PEERS: usize = 8;
+
+//the canonical form of a dialable address: an ip and a non-zero port. host names are not resolved, so a peer can't
+//point us at arbitrary names.
+pub fn parse_peer_addr(addr: &str) -> Option<String> {
+    let socket_addr: SocketAddr = addr.parse().ok()?;
+    let ip = socket_addr.ip();
+    if socket_addr.port() == 0 || ip.is_unspecified() || ip.is_multicast() {
+        return None;
+    }
+    Some(socket_addr.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,  //the peer connected to us; keyed by its ephemeral socket address.
+    Outbound, //we dial the peer; keyed by its listening address.
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Known,        //learned from the command line or from gossip, never reached yet.
+    Connected,    //the last handshake succeeded.
+    Disconnected, //the last connection attempt or connection failed.
+    Unreachable,  //we gave up dialing it after too many failed attempts.
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: String,
+    pub direction: Direction,
+    pub state: ConnectionState,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub listen_addr: Option<String>, //where the peer accepts connections, as announced in its version.
+    pub user_agent: Option<String>,
+    pub best_height: u64,
+    rank: u64, //random, orders the candidates we haven't reached yet so gossip can't pick which ones we dial.
+}
+
+impl PeerInfo {
+    fn new(addr: String, direction: Direction) -> Self {
+        Self {
+            addr,
+            direction,
+            state: ConnectionState::Known,
+            last_seen: None,
+            listen_addr: None,
+            user_agent: None,
+            best_height: 0,
+            rank: rand::random(),
+        }
+    }
+}
+
+//every peer we know of: outbound entries form the address book we dial and gossip,
+//inbound entries only live as long as their connection.
+#[derive(Debug, Default)]
+pub struct PeerBook {
+    peers: HashMap<String, PeerInfo>,
+    own_addr: Option<String>,
+}
+
+impl PeerBook {
+    pub fn set_own_addr(&mut self, addr: String) {
+        self.peers.remove(&addr);
+        self.own_addr = Some(addr);
+    }
+
+    //adds a dialable address unless it is invalid, ours, already known or the book is full; returns whether it
+    //was new.
+    pub fn add_address(&mut self, addr: String) -> bool {
+        let Some(addr) = parse_peer_addr(&addr) else {
+            return false;
+        };
+        if self.own_addr.as_ref() == Some(&addr) || self.peers.contains_key(&addr) {
+            return false;
+        }
+        let book_size = self
+            .peers
+            .values()
+            .filter(|peer| peer.direction == Direction::Outbound)
+            .count();
+        if book_size >= MAX_ADDRESS_BOOK_SIZE {
+            //make room by forgetting one we gave up on, if there is one
+            let Some(unreachable) = self
+                .peers
+                .values()
+                .find(|peer| peer.state == ConnectionState::Unreachable)
+                .map(|peer| peer.addr.clone())
+            else {
+                return false;
+            };
+            self.peers.remove(&unreachable);
+        }
+        self.peers
+            .insert(addr.clone(), PeerInfo::new(addr, Direction::Outbound));
+        true
+    }
+
+    pub fn mark_connected(&mut self, addr: &str, direction: Direction, version: &VersionInfo) {
+        let peer = self
+            .peers
+            .entry(addr.to_string())
+            .or_insert_with(|| PeerInfo::new(addr.to_string(), direction));
+        peer.state = ConnectionState::Connected;
+        peer.last_seen = Some(Utc::now());
+        peer.listen_addr = version.listen_addr.as_deref().and_then(parse_peer_addr);
+        peer.user_agent = Some(version.user_agent.clone());
+        peer.best_height = version.best_height;
+
+        //an inbound peer tells us where to reach it, which makes it a dialable peer as well, even one we gave up on
+        if direction == Direction::Inbound {
+            if let Some(listen_addr) = peer.listen_addr.clone() {
+                if let Some(known) = self.peers.get_mut(&listen_addr) {
+                    if known.state == ConnectionState::Unreachable {
+                        known.state = ConnectionState::Known;
+                    }
+                } else {
+                    self.add_address(listen_addr);
+                }
+            }
+        }
+    }
+
+    pub fn mark_seen(&mut self, addr: &str) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.last_seen = Some(Utc::now());
+        }
+    }
+
+    pub fn mark_disconnected(&mut self, addr: &str) {
+        let Some(peer) = self.peers.get_mut(addr) else {
+            return;
+        };
+        match peer.direction {
+            Direction::Inbound => {
+                self.peers.remove(addr);
+            }
+            Direction::Outbound => peer.state = ConnectionState::Disconnected,
+        }
+    }
+
+    pub fn mark_unreachable(&mut self, addr: &str) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.state = ConnectionState::Unreachable;
+        }
+    }
+
+    pub fn get(&self, addr: &str) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    pub fn all(&self) -> Vec<PeerInfo> {
+        self.peers.values().cloned().collect()
+    }
+
+    //addresses to dial, leaving out the ones we gave up on, best first: the ones we are connected to, then the ones
+    //we reached before, most recent first, then a random pick of the rest. callers dial only the first few.
+    pub fn outbound_addresses(&self) -> Vec<String> {
+        let mut peers: Vec<&PeerInfo> = self
+            .peers
+            .values()
+            .filter(|peer| {
+                peer.direction == Direction::Outbound && peer.state != ConnectionState::Unreachable
+            })
+            .collect();
+        peers.sort_by_key(|peer| {
+            (
+                peer.state != ConnectionState::Connected,
+                std::cmp::Reverse(peer.last_seen),
+                peer.rank,
+            )
+        });
+        peers.into_iter().map(|peer| peer.addr.clone()).collect()
+    }
+
+    //addresses worth sharing with other nodes: our own, then reachable ones by recency, never the ones that keep failing.
+    pub fn addresses_for_gossip(&self) -> Vec<String> {
+        let mut peers: Vec<&PeerInfo> = self
+            .peers
+            .values()
+            .filter(|peer| {
+                peer.direction == Direction::Outbound
+                    && matches!(
+                        peer.state,
+                        ConnectionState::Known | ConnectionState::Connected
+                    )
+            })
+            .collect();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        self.own_addr
+            .iter()
+            .cloned()
+            .chain(peers.into_iter().map(|peer| peer.addr.clone()))
+            .take(MAX_ADDR_PER_MESSAGE)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_ip_addresses_with_a_port_are_dialable() {
+        assert_eq!(
+            parse_peer_addr("127.0.0.1:8000").as_deref(),
+            Some("127.0.0.1:8000")
+        );
+        assert_eq!(parse_peer_addr("[::1]:8000").as_deref(), Some("[::1]:8000"));
+        for addr in [
+            "example.com:8000",
+            "127.0.0.1",
+            "127.0.0.1:0",
+            "0.0.0.0:8000",
+            "",
+        ] {
+            assert_eq!(parse_peer_addr(addr), None, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn address_book_is_capped() {
+        let mut book = PeerBook::default();
+        book.set_own_addr(String::from("10.0.0.1:1"));
+        assert!(!book.add_address(String::from("10.0.0.1:1")));
+        assert!(!book.add_address(String::from("not an address")));
+        for port in 1..=MAX_ADDRESS_BOOK_SIZE as u16 {
+            assert!(book.add_address(format!("10.0.0.2:{}", port)));
+        }
+        assert!(!book.add_address(String::from("10.0.0.3:1")));
+        assert_eq!(book.outbound_addresses().len(), MAX_ADDRESS_BOOK_SIZE);
+
+        book.mark_unreachable("10.0.0.2:1");
+        assert!(book.add_address(String::from("10.0.0.3:1")));
+        assert!(book.get("10.0.0.2:1").is_none());
+    }
+
+    #[test]
+    fn connected_peers_are_dialed_first() {
+        let mut book = PeerBook::default();
+        for port in 1..=20 {
+            book.add_address(format!("10.0.0.2:{}", port));
+        }
+        let version = VersionInfo {
+            protocol_version: 0,
+            user_agent: String::new(),
+            genesis_hash: String::new(),
+            best_height: 0,
+            listen_addr: None,
+        };
+        book.mark_connected("10.0.0.2:17", Direction::Outbound, &version);
+
+        let addresses = book.outbound_addresses();
+        assert_eq!(addresses[0], "10.0.0.2:17");
+        assert_eq!(addresses, book.outbound_addresses()); //the order is stable
+    }
+
+    #[test]
+    fn unreachable_peers_are_not_dialed_until_they_connect_to_us() {
+        let mut book = PeerBook::default();
+        book.add_address(String::from("10.0.0.2:8000"));
+        book.mark_unreachable("10.0.0.2:8000");
+        assert!(book.outbound_addresses().is_empty());
+        assert!(book.addresses_for_gossip().is_empty());
+
+        let version = VersionInfo {
+            protocol_version: 0,
+            user_agent: String::new(),
+            genesis_hash: String::new(),
+            best_height: 0,
+            listen_addr: Some(String::from("10.0.0.2:8000")),
+        };
+        book.mark_connected("10.0.0.2:50000", Direction::Inbound, &version);
+        assert_eq!(
+            book.outbound_addresses(),
+            vec![String::from("10.0.0.2:8000")]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use super::Target;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+//an epoch can move the target by at most this factor either way.
+pub const MAX_EPOCH_ADJUSTMENT: u64 = 4;
+//a single lwma solve time counts as at most this many target times.
+pub const MAX_SOLVE_TIME_FACTOR: u64 = 6;
+//longest epoch interval or lwma window a chain spec may ask for; the history is walked for every block.
+pub const MAX_RETARGET_BLOCKS: u64 = 4096;
+
+//what a retarget algorithm needs to know about one block of the chain.
+#[derive(Debug, Clone, Copy)]
+pub struct RetargetSample {
+    pub height: u64,
+    pub timestamp: u64, //seconds since the unix epoch
+    pub target: Target, //target the block was mined at
+}
+
+//the algorithm that derives the next target from chain history, picked by the chain spec.
+//it only looks at block heights, timestamps and targets using integer math, so every node recomputes the same target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Retarget {
+    //bitcoin style: the target stays fixed for `interval` blocks, then scales by how long the epoch actually took.
+    Epoch { interval: u64 },
+    //linearly weighted moving average over the last `window` solve times, recent blocks weighing the most.
+    Lwma { window: u64 },
+}
+
+impl Retarget {
+    //how many of the most recent blocks, oldest first, `next_target` wants to see.
+    pub fn history_len(&self) -> usize {
+        match self {
+            Retarget::Epoch { interval } => *interval as usize + 1,
+            Retarget::Lwma { window } => *window as usize + 1,
+        }
+    }
+
+    //target for the child of the last block in `history`; never easier than `pow_limit`.
+    pub fn next_target(
+        &self,
+        history: &[RetargetSample],
+        target_time: u64,
+        pow_limit: Target,
+    ) -> Target {
+        let Some(last) = history.last() else {
+            return pow_limit;
+        };
+        let next_target = match self {
+            Retarget::Epoch { interval } => epoch(history, last, *interval, target_time),
+            Retarget::Lwma { .. } => lwma(history, last, target_time),
+        };
+        next_target.min(pow_limit).normalized()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Retarget::Epoch { interval: 0 } => Err(anyhow!("Epoch interval must be positive")),
+            Retarget::Epoch { interval } if *interval > MAX_RETARGET_BLOCKS => Err(anyhow!(
+                "Epoch interval must be at most {} blocks",
+                MAX_RETARGET_BLOCKS
+            )),
+            Retarget::Lwma { window } if *window < 2 => {
+                Err(anyhow!("LWMA window must be at least 2 blocks"))
+            }
+            Retarget::Lwma { window } if *window > MAX_RETARGET_BLOCKS => Err(anyhow!(
+                "LWMA window must be at most {} blocks",
+                MAX_RETARGET_BLOCKS
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for Retarget {
+    fn default() -> Self {
+        Retarget::Lwma { window: 30 }
+    }
+}
+
+fn epoch(
+    history: &[RetargetSample],
+    last: &RetargetSample,
+    interval: u64,
+    target_time: u64,
+) -> Target {
+    //only the last block of an epoch retargets, and only once a whole epoch is in the history
+    if !(last.height + 1).is_multiple_of(interval) || history.len() < interval as usize + 1 {
+        return last.target;
+    }
+    let first = &history[history.len() - 1 - interval as usize];
+
+    //timestamps come from peers, so nothing here may overflow whatever they hold
+    let expected = interval.saturating_mul(target_time);
+    let actual = last.timestamp.saturating_sub(first.timestamp);
+    let clamped = actual.clamp(
+        expected / MAX_EPOCH_ADJUSTMENT,
+        expected.saturating_mul(MAX_EPOCH_ADJUSTMENT),
+    );
+    println!(
+        "Epoch retarget at height {}: took {} seconds, expected {}",
+        last.height, actual, expected
+    );
+    last.target.scale(clamped, expected)
+}
+
+//next = average target * weighted solve time / (sum of weights * target time), so a window of on-time blocks keeps the target.
+fn lwma(history: &[RetargetSample], last: &RetargetSample, target_time: u64) -> Target {
+    if history.len() < 2 {
+        return last.target;
+    }
+
+    let max_solve_time = MAX_SOLVE_TIME_FACTOR.saturating_mul(target_time).max(1);
+    let mut weighted_solve_time = 0u64;
+    let mut weights = 0u64;
+    for (weight, pair) in (1u64..).zip(history.windows(2)) {
+        //clamped so that a skewed timestamp can't swing the target on its own
+        let solve_time = pair[1]
+            .timestamp
+            .saturating_sub(pair[0].timestamp)
+            .clamp(1, max_solve_time);
+        weighted_solve_time = weighted_solve_time.saturating_add(weight.saturating_mul(solve_time));
+        weights += weight;
+    }
+
+    let targets: Vec<Target> = history[1..].iter().map(|sample| sample.target).collect();
+    Target::average(&targets).scale(weighted_solve_time, weights.saturating_mul(target_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(timestamps: &[u64], target: Target) -> Vec<RetargetSample> {
+        (0..)
+            .zip(timestamps)
+            .map(|(height, timestamp)| RetargetSample {
+                height,
+                timestamp: *timestamp,
+                target,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn on_time_blocks_keep_the_target() {
+        let target = Target::from_compact(0x1f00ffff).unwrap();
+        let history = samples(&[0, 10, 20, 30, 40], target);
+        let lwma = Retarget::Lwma { window: 4 };
+        assert_eq!(lwma.next_target(&history, 10, Target::MAX), target);
+        let epoch = Retarget::Epoch { interval: 4 };
+        assert_eq!(epoch.next_target(&history, 10, Target::MAX), target);
+    }
+
+    #[test]
+    fn extreme_timestamps_are_clamped_instead_of_overflowing() {
+        let target = Target::from_compact(0x1f00ffff).unwrap();
+        let pow_limit = Target::from_compact(0x2000ffff).unwrap();
+        for timestamps in [[0, 10, 20, 30, u64::MAX], [0, u64::MAX, 1 << 63, 1, 0]] {
+            let history = samples(&timestamps, target);
+            for retarget in [
+                Retarget::Lwma { window: 4 },
+                Retarget::Epoch { interval: 4 },
+            ] {
+                let next = retarget.next_target(&history, 10, pow_limit);
+                assert!(next <= pow_limit);
+            }
+        }
+    }
+
+    #[test]
+    fn history_lengths_are_bounded() {
+        for retarget in [
+            Retarget::Epoch { interval: 0 },
+            Retarget::Epoch {
+                interval: MAX_RETARGET_BLOCKS + 1,
+            },
+            Retarget::Lwma { window: 1 },
+            Retarget::Lwma { window: u64::MAX },
+        ] {
+            assert!(retarget.validate().is_err(), "{:?}", retarget);
+        }
+        assert!(Retarget::Epoch {
+            interval: MAX_RETARGET_BLOCKS
+        }
+        .validate()
+        .is_ok());
+        assert!(Retarget::Lwma { window: 2 }.validate().is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+use super::{Block, Blockchain, Message, Network};
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use std::cmp::Reverse;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+//how many blocks we ask a peer for in one getblocks request.
+pub const SYNC_BATCH_SIZE: u32 = 100;
+//room kept in a blocks reply for the envelope around the blocks themselves.
+pub const ENVELOPE_OVERHEAD: usize = 1024;
+
+//hashes of our active chain from the tip backwards: the last 10 one by one, then doubling the step, always ending with genesis.
+//a peer walks the list and answers from the first hash it also has on its active chain.
+pub fn build_locator(chain: &[Block]) -> Vec<String> {
+    let mut locator = Vec::new();
+    let mut index = chain.len() as i64 - 1;
+    let mut step = 1;
+    while index > 0 {
+        locator.push(chain[index as usize].hash.clone());
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        index -= step;
+    }
+    if let Some(genesis) = chain.first() {
+        locator.push(genesis.hash.clone());
+    }
+    locator
+}
+
+//handshakes with the peers we dial, all at once and each bounded by the handshake timeout, to learn their best
+//heights, then downloads from the highest ones first until no peer claims more blocks than we have.
+//the blockchain lock is only taken to build requests and connect replies, never while waiting on a peer.
+pub async fn synchronize(blockchain: &Arc<Mutex<Blockchain>>, network: &Network) {
+    let peers = network.get_peers().await;
+    let versions = join_all(peers.iter().map(|peer| network.handshake(peer))).await;
+    let mut candidates = Vec::new();
+    for (peer, version) in peers.into_iter().zip(versions) {
+        match version {
+            Ok(version) => candidates.push((peer, version.best_height)),
+            Err(err) => eprintln!("Error: Handshake with {} failed: {}", peer, err),
+        }
+    }
+    candidates.sort_by_key(|&(_, best_height)| Reverse(best_height));
+
+    for (peer, best_height) in candidates {
+        let our_height = blockchain.lock().await.chain.len() as u64 - 1;
+        if best_height <= our_height {
+            println!("Already in sync with {} (height {})", peer, best_height);
+            continue;
+        }
+        match sync_with_peer(blockchain, network, &peer).await {
+            Ok(count) => println!("Downloaded {} blocks from {}", count, peer),
+            Err(err) => eprintln!("Error: Sync with {} failed: {}", peer, err),
+        }
+    }
+    blockchain.lock().await.needs_sync = false;
+}
+
+pub async fn sync_with_peer(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    network: &Network,
+    peer: &str,
+) -> Result<usize> {
+    let mut downloaded = 0;
+    loop {
+        let locator = build_locator(&blockchain.lock().await.chain);
+        let request = Message::GetBlocks {
+            locator,
+            limit: SYNC_BATCH_SIZE,
+        };
+
+        let blocks = match network.request(peer, &request).await? {
+            Message::Blocks(blocks) => blocks,
+            Message::Reject { reason } => return Err(anyhow!("request rejected: {}", reason)),
+            other => return Err(anyhow!("unexpected {} reply", other.name())),
+        };
+        let mut blockchain = blockchain.lock().await;
+        let mut connected = 0;
+        for block in blocks {
+            if blockchain.block_tree.contains(&block.hash) {
+                continue;
+            }
+            if !blockchain.block_tree.contains(&block.previous_hash) {
+                return Err(anyhow!(
+                    "peer's chain does not connect to ours at block {}",
+                    block.index
+                ));
+            }
+            if let Err(err) = blockchain.accept_block(block) {
+                if let Some(misbehavior) = Blockchain::misbehavior_for_rejection(&err) {
+                    network.report_misbehavior(peer, misbehavior).await;
+                }
+                return Err(anyhow!("invalid block from peer: {}", err));
+            }
+            connected += 1;
+        }
+        downloaded += connected;
+
+        //batches can come back short because of the frame size limit, so only an unproductive one, empty included,
+        //means the peer has nothing more for us.
+        if connected == 0 {
+            return Ok(downloaded);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::fmt;
+
+//a 256-bit proof-of-work target: a block is valid when its hash, read as a big-endian number, is at most the target.
+//stored as four 64-bit limbs, least significant first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Target([u64; 4]);
+
+impl Target {
+    pub const MAX: Target = Target([u64::MAX; 4]);
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (i, chunk) in bytes.chunks_exact(8).enumerate() {
+            limbs[3 - i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        Target(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, chunk) in bytes.chunks_exact_mut(8).enumerate() {
+            chunk.copy_from_slice(&self.0[3 - i].to_be_bytes());
+        }
+        bytes
+    }
+
+    //decodes the compact "bits" form: the top byte is the length of the target in bytes, the low three bytes its leading digits.
+    //like bitcoin's nBits, 0x1d00ffff is 0x00ffff followed by 26 zero bytes.
+    pub fn from_compact(bits: u32) -> Result<Self> {
+        let size = bits >> 24;
+        let mantissa = bits & 0x007f_ffff;
+        if bits & 0x0080_0000 != 0 && mantissa != 0 {
+            return Err(anyhow!("compact target {:08x} is negative", bits));
+        }
+        if mantissa != 0
+            && (size > 34 || (mantissa > 0xff && size > 33) || (mantissa > 0xffff && size > 32))
+        {
+            return Err(anyhow!("compact target {:08x} overflows 256 bits", bits));
+        }
+
+        let target = if size <= 3 {
+            Target::from_u64((mantissa >> (8 * (3 - size))) as u64)
+        } else {
+            Target::from_u64(mantissa as u64).shl(8 * (size - 3))
+        };
+        if target.is_zero() {
+            return Err(anyhow!("compact target {:08x} is zero", bits));
+        }
+        Ok(target)
+    }
+
+    //the compact form keeps only the three leading bytes, so encoding rounds the target down.
+    pub fn to_compact(self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut mantissa = if size <= 3 {
+            (self.0[0] << (8 * (3 - size))) as u32
+        } else {
+            self.shr(8 * (size - 3)).0[0] as u32
+        };
+        //the mantissa's top bit is a sign bit, so move a set one into the next byte
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        mantissa | (size << 24)
+    }
+
+    //the nearest target the compact form can represent, as stored in block headers.
+    pub fn normalized(&self) -> Self {
+        Target::from_compact(self.to_compact()).unwrap_or(*self)
+    }
+
+    //whether a hex-encoded sha256 hash satisfies this target.
+    pub fn is_met_by(&self, hash: &str) -> bool {
+        let Ok(bytes) = hex::decode(hash) else {
+            return false;
+        };
+        let Ok(bytes) = <[u8; 32]>::try_from(bytes) else {
+            return false;
+        };
+        Target::from_be_bytes(bytes) <= *self
+    }
+
+    //expected number of hashes to find one at or below the target: 2^256 / (target + 1), saturating at u128::MAX.
+    pub fn work(&self) -> u128 {
+        //2^256 itself doesn't fit, but 2^256 / (t + 1) == (2^256 - t - 1) / (t + 1) + 1
+        let divisor = self.add(&Target::from_u64(1));
+        if divisor.is_zero() {
+            return 1; //the target is MAX, every hash meets it.
+        }
+        let work = self.not().div(&divisor).add(&Target::from_u64(1));
+        if work.0[2] != 0 || work.0[3] != 0 {
+            return u128::MAX;
+        }
+        (work.0[1] as u128) << 64 | work.0[0] as u128
+    }
+
+    //target * numerator / denominator without losing the high bits, saturating at MAX.
+    pub fn scale(&self, numerator: u64, denominator: u64) -> Self {
+        let mut product = [0u64; 5];
+        let mut carry = 0u128;
+        for (i, limb) in self.0.iter().enumerate() {
+            let value = *limb as u128 * numerator as u128 + carry;
+            product[i] = value as u64;
+            carry = value >> 64;
+        }
+        product[4] = carry as u64;
+
+        let mut quotient = [0u64; 5];
+        let mut remainder = 0u128;
+        for i in (0..5).rev() {
+            let value = remainder << 64 | product[i] as u128;
+            quotient[i] = (value / denominator as u128) as u64;
+            remainder = value % denominator as u128;
+        }
+        if quotient[4] != 0 {
+            return Target::MAX;
+        }
+        Target([quotient[0], quotient[1], quotient[2], quotient[3]])
+    }
+
+    //mean of the targets, rounded down; each one is divided first so the sum can't overflow.
+    pub fn average(targets: &[Target]) -> Self {
+        let count = targets.len().max(1) as u64;
+        targets.iter().fold(Target::default(), |sum, target| {
+            sum.add(&target.scale(1, count))
+        })
+    }
+
+    //how many times harder this target is than `limit`, for display only.
+    pub fn difficulty(&self, limit: &Target) -> f64 {
+        limit.to_f64() / self.to_f64()
+    }
+
+    fn from_u64(value: u64) -> Self {
+        Target([value, 0, 0, 0])
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.iter().all(|limb| *limb == 0)
+    }
+
+    //number of significant bits.
+    fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    fn to_f64(self) -> f64 {
+        self.0
+            .iter()
+            .rev()
+            .fold(0.0, |acc, limb| acc * 2f64.powi(64) + *limb as f64)
+    }
+
+    fn not(&self) -> Self {
+        Target(self.0.map(|limb| !limb))
+    }
+
+    //wrapping addition.
+    fn add(&self, other: &Target) -> Self {
+        let mut sum = [0u64; 4];
+        let mut carry = false;
+        for (limb, (a, b)) in sum.iter_mut().zip(self.0.iter().zip(other.0)) {
+            let (value, overflow1) = a.overflowing_add(b);
+            let (value, overflow2) = value.overflowing_add(carry as u64);
+            *limb = value;
+            carry = overflow1 || overflow2;
+        }
+        Target(sum)
+    }
+
+    //wrapping subtraction.
+    fn sub(&self, other: &Target) -> Self {
+        self.add(&other.not()).add(&Target::from_u64(1))
+    }
+
+    fn shl(&self, shift: u32) -> Self {
+        let mut result = [0u64; 4];
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in result.iter_mut().enumerate().skip(limbs) {
+            *limb = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                *limb |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        Target(result)
+    }
+
+    fn shr(&self, shift: u32) -> Self {
+        let mut result = [0u64; 4];
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in result
+            .iter_mut()
+            .enumerate()
+            .take(4usize.saturating_sub(limbs))
+        {
+            *limb = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                *limb |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        Target(result)
+    }
+
+    //long division, one bit at a time; the divisor must not be zero.
+    fn div(&self, divisor: &Target) -> Self {
+        let mut quotient = Target::default();
+        let mut remainder = Target::default();
+        for bit in (0..256).rev() {
+            let overflow = remainder.0[3] >> 63 == 1; //the shift below pushes a bit out of 256
+            remainder = remainder.shl(1);
+            remainder.0[0] |= (self.0[bit / 64] >> (bit % 64)) & 1;
+            if overflow || remainder >= *divisor {
+                remainder = remainder.sub(divisor);
+                quotient.0[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        quotient
+    }
+}
+
+impl Ord for Target {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for Target {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_be_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_form_round_trips() {
+        let target = Target::from_compact(0x1d00ffff).unwrap();
+        let mut expected = [0u8; 32];
+        expected[4..6].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(target.to_be_bytes(), expected);
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        assert_eq!(
+            Target::from_compact(0x2000ffff).unwrap().to_compact(),
+            0x2000ffff
+        );
+    }
+
+    #[test]
+    fn negative_zero_and_overflowing_bits_are_rejected() {
+        assert!(Target::from_compact(0x1d800001).is_err()); //sign bit set
+        assert!(Target::from_compact(0x1d000000).is_err()); //zero
+        assert!(Target::from_compact(0x23000001).is_err()); //35 bytes
+        assert!(Target::from_compact(0x22000100).is_err()); //two significant bytes in 34
+        assert!(Target::from_compact(0x21010000).is_err()); //three significant bytes in 33
+        assert!(Target::from_compact(0x2100ffff).is_ok());
+    }
+
+    #[test]
+    fn work_is_two_to_the_256_over_target_plus_one() {
+        assert_eq!(Target::MAX.work(), 1);
+        let mut half = [0xffu8; 32];
+        half[0] = 0x7f; //2^255 - 1
+        assert_eq!(Target::from_be_bytes(half).work(), 2);
+        let mut bytes = [0xffu8; 32];
+        bytes[..4].fill(0); //2^224 - 1
+        assert_eq!(Target::from_be_bytes(bytes).work(), 1 << 32);
+        assert_eq!(Target::from_u64(1).work(), u128::MAX);
+    }
+
+    #[test]
+    fn scale_keeps_high_bits_and_saturates() {
+        let target = Target::from_compact(0x1d00ffff).unwrap();
+        assert_eq!(target.scale(3, 3), target);
+        assert_eq!(target.scale(2, 1).scale(1, 2), target);
+        assert_eq!(Target::from_u64(10).scale(1, 3), Target::from_u64(3));
+        assert_eq!(Target::MAX.scale(2, 1), Target::MAX);
+        //a product wider than 256 bits still divides back down exactly
+        assert_eq!(Target::MAX.scale(4, 4), Target::MAX);
+    }
+}
@@ -1,45 +1,169 @@
+use super::{Amount, Wallet};
 use anyhow::{Ok, Result};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+//points at one output of an earlier transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: String, //hash of the transaction
+    pub vout: u32,    //index of the output in it
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxOutput {
+    pub address: String,
+    pub amount: Amount,
+}
+
+impl TxOutput {
+    pub fn new(address: String, amount: Amount) -> Self {
+        Self { address, amount }
+    }
+}
+
+//spends unspent outputs of the sender and creates new outputs; what the inputs hold beyond the outputs and the fee
+//is burned, so the sender pays the change back to itself.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
-    pub sender: String,
-    pub receiver: String,
-    pub amount: f64,
+    pub sender: String, //owns every input and signs the transaction
+    pub inputs: Vec<OutPoint>,
+    pub outputs: Vec<TxOutput>,
+    #[serde(default)]
+    pub fee: Amount, //collected by the miner of the block.
     pub is_reward: bool, //miner's reward
+    #[serde(default)]
+    pub height: u64, //of the block paying a reward, which keeps reward ids unique; 0 for transfers
+    #[serde(default)]
+    pub nonce: u64, //the sender's sequence number: 0 for its first transaction, then one more for each after it
     pub signature: Option<String>,
 }
 
 impl Transaction {
-    pub fn new(sender: String, receiver: String, amount: f64) -> Self {
+    pub fn new(sender: String, inputs: Vec<OutPoint>, outputs: Vec<TxOutput>) -> Self {
         Self {
             sender: sender,
-            receiver: receiver,
-            amount,
+            inputs,
+            outputs,
+            fee: Amount::ZERO,
             is_reward: false,
+            height: 0,
+            nonce: 0,
             signature: None,
         }
     }
-    pub fn reward(receiver: String, amount: f64) -> Self {
+
+    //pays `amount` to `receiver` from the first of `unspent` that cover it and the fee, with the change going back
+    //to the sender. unsigned.
+    pub fn transfer(
+        sender: String,
+        unspent: &[(OutPoint, TxOutput)],
+        receiver: String,
+        amount: Amount,
+        fee: Amount,
+    ) -> Result<Self> {
+        let needed = amount.checked_add(fee)?;
+        let mut inputs = Vec::new();
+        let mut total = Amount::ZERO;
+        for (outpoint, output) in unspent {
+            if total >= needed {
+                break;
+            }
+            inputs.push(outpoint.clone());
+            total = total.checked_add(output.amount)?;
+        }
+        if total < needed {
+            return Err(anyhow::anyhow!(
+                "Insufficient funds for {}: has {}, needs {}",
+                sender,
+                total,
+                needed
+            ));
+        }
+
+        let mut transaction = Self::new(sender.clone(), inputs, Vec::new()).with_fee(fee);
+        transaction.outputs.push(TxOutput::new(receiver, amount));
+        let change = total.checked_sub(needed)?;
+        if !change.is_zero() {
+            transaction.outputs.push(TxOutput::new(sender, change));
+        }
+        Ok(transaction)
+    }
+
+    pub fn reward(height: u64, outputs: Vec<TxOutput>) -> Self {
         Self {
             sender: String::from("Network"),
-            receiver: receiver,
-            amount,
+            inputs: Vec::new(),
+            outputs,
+            fee: Amount::ZERO,
             is_reward: true,
+            height,
+            nonce: 0,
             signature: None,
         }
     }
 
-    pub fn sign(&mut self, signing_key: &SigningKey) -> anyhow::Result<()> {
-        let raw_transaction_data = self.get_raw_transaction_data();
-        let signature = signing_key.sign(raw_transaction_data.as_bytes());
+    pub fn with_fee(mut self, fee: Amount) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn total_output(&self) -> Result<Amount> {
+        Amount::checked_sum(self.outputs.iter().map(|output| output.amount))
+    }
+
+    //what the inputs have to cover.
+    pub fn total_cost(&self) -> Result<Amount> {
+        self.total_output()?.checked_add(self.fee)
+    }
+
+    //the outputs this transaction creates, by the outpoint that spends them.
+    pub fn created_outputs(&self) -> impl Iterator<Item = (OutPoint, &TxOutput)> {
+        let txid = self.get_hash();
+        (0..).zip(&self.outputs).map(move |(vout, output)| {
+            (
+                OutPoint {
+                    txid: txid.clone(),
+                    vout,
+                },
+                output,
+            )
+        })
+    }
+
+    //bytes the transaction takes up in a block, as serialized on the wire.
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map_or(0, |bytes| bytes.len())
+    }
+
+    //fee per byte in base units, what block assembly ranks transactions by.
+    pub fn fee_rate(&self) -> f64 {
+        self.fee.units() as f64 / self.size().max(1) as f64
+    }
+
+    //`chain_id` is the genesis hash of the network the transaction is meant for; it is signed along with the
+    //transaction, so the signature doesn't hold on any other network.
+    pub fn sign(&mut self, signing_key: &SigningKey, chain_id: &str) -> anyhow::Result<()> {
+        let signature = signing_key.sign(&self.get_signing_payload(chain_id));
         self.signature = Some(hex::encode(signature.to_bytes()));
 
         Ok(())
     }
 
-    pub fn verify(&self, verifying_key: &VerifyingKey) -> Result<()> {
+    //checks the signature against the sender's public key, which the address encodes, so it holds only when the
+    //sender's wallet signed the transaction.
+    pub fn verify(&self, chain_id: &str) -> Result<()> {
+        let verifying_key = Wallet::decode_address(&self.sender)?;
+        self.verify_with_key(&verifying_key, chain_id)
+    }
+
+    fn verify_with_key(&self, verifying_key: &VerifyingKey, chain_id: &str) -> Result<()> {
         let signature_str = self
             .signature
             .as_ref()
@@ -53,12 +177,97 @@ impl Transaction {
 
         let signature = Signature::from_bytes(signature_array);
 
-        verifying_key.verify(self.get_raw_transaction_data().as_bytes(), &signature)?;
+        verifying_key.verify(&self.get_signing_payload(chain_id), &signature)?;
 
         Ok(())
     }
 
-    pub fn get_raw_transaction_data(&self) -> String {
-        format!("{}->{}:{}", self.sender, self.receiver, self.amount)
+    pub fn get_signing_payload(&self, chain_id: &str) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_bytes(&mut payload, chain_id.as_bytes());
+        payload.extend(self.encode());
+        payload
+    }
+
+    //canonical encoding of everything but the signature. integers are big-endian, and strings and lists are
+    //prefixed with their length as a u32, so no two different transactions encode to the same bytes.
+    //  sender, nonce, inputs (txid, vout), outputs (address, amount in base units), fee, height, is_reward
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_bytes(&mut bytes, self.sender.as_bytes());
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in &self.inputs {
+            put_bytes(&mut bytes, input.txid.as_bytes());
+            bytes.extend_from_slice(&input.vout.to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.outputs.len() as u32).to_be_bytes());
+        for output in &self.outputs {
+            put_bytes(&mut bytes, output.address.as_bytes());
+            bytes.extend_from_slice(&output.amount.units().to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.fee.units().to_be_bytes());
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.push(self.is_reward as u8);
+        bytes
+    }
+
+    //identifies the transaction on the network, used to recognise one we have already seen.
+    pub fn get_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.encode());
+        let mut signature = Vec::new();
+        put_bytes(
+            &mut signature,
+            self.signature.as_deref().unwrap_or_default().as_bytes(),
+        );
+        hasher.update(signature);
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(address: &str, amount: &str) -> TxOutput {
+        TxOutput::new(address.to_string(), amount.parse().unwrap())
+    }
+
+    #[test]
+    fn moving_output_boundaries_changes_the_encoding() {
+        let split = Transaction::new(
+            String::from("S"),
+            Vec::new(),
+            vec![output("R", "30"), output("S", "69.99")],
+        );
+        let merged = Transaction::new(
+            String::from("S"),
+            Vec::new(),
+            vec![output("R:30,S", "69.99")],
+        );
+        assert_ne!(split.encode(), merged.encode());
+        assert_ne!(split.get_hash(), merged.get_hash());
+    }
+
+    #[test]
+    fn signature_covers_outputs_and_chain_id() {
+        let wallet = Wallet::new();
+        let mut transaction = Transaction::new(
+            wallet.address.clone(),
+            Vec::new(),
+            vec![output(&Wallet::new().address, "30")],
+        );
+        transaction.sign(&wallet.signing_key, "chain").unwrap();
+        assert!(transaction.verify("chain").is_ok());
+        assert!(transaction.verify("other chain").is_err());
+
+        transaction.outputs[0].amount = "31".parse().unwrap();
+        assert!(transaction.verify("chain").is_err());
     }
 }
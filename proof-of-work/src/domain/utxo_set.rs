@@ -0,0 +1,243 @@
+use super::{Amount, Block, OutPoint, TxOutput};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+//deepest reorganization that can be undone: what blocks further below the tip spent is forgotten.
+pub const MAX_REORG_DEPTH: u64 = 288;
+
+//an output nobody has spent yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Utxo {
+    pub output: TxOutput,
+    pub height: u64,     //of the block that created it
+    pub is_reward: bool, //created by a block's reward, so subject to coinbase maturity
+}
+
+//the unspent outputs of the active chain, and the next nonce of every address that has sent a transaction. kept up
+//to date as blocks are connected and disconnected, with what each connected block spent, so disconnecting it during
+//a reorganization puts the outputs back.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, Utxo>,
+    nonces: HashMap<String, u64>,
+    undo: HashMap<String, (u64, Vec<(OutPoint, Utxo)>)>, //height and spent outputs, by the hash of the block spending them
+    coinbase_maturity: u64,
+}
+
+impl UtxoSet {
+    pub fn new(coinbase_maturity: u64) -> Self {
+        Self {
+            coinbase_maturity,
+            ..Self::default()
+        }
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.utxos.get(outpoint)
+    }
+
+    //whether `utxo` may be spent in the block at `height`. the premine of the genesis block is spendable right away.
+    pub fn is_mature(&self, utxo: &Utxo, height: u64) -> bool {
+        !utxo.is_reward
+            || utxo.height == 0
+            || utxo.height.saturating_add(self.coinbase_maturity) <= height
+    }
+
+    //the nonce the next transaction of `address` has to carry.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).copied().unwrap_or_default()
+    }
+
+    //can't overflow: validated blocks never create more than the monetary policy's max_supply.
+    pub fn balance(&self, address: &str) -> Amount {
+        let amounts = self
+            .utxos
+            .values()
+            .filter(|utxo| utxo.output.address == address)
+            .map(|utxo| utxo.output.amount);
+        Amount::checked_sum(amounts).unwrap_or(Amount::MAX)
+    }
+
+    //outputs of `address` that can be spent in the block at `height`, oldest first.
+    pub fn spendable_outputs(&self, address: &str, height: u64) -> Vec<(OutPoint, TxOutput)> {
+        let mut outputs: Vec<(&OutPoint, &Utxo)> = self
+            .utxos
+            .iter()
+            .filter(|(_, utxo)| utxo.output.address == address && self.is_mature(utxo, height))
+            .collect();
+        outputs.sort_by(|(a, x), (b, y)| x.height.cmp(&y.height).then_with(|| a.txid.cmp(&b.txid)));
+        outputs
+            .into_iter()
+            .map(|(outpoint, utxo)| (outpoint.clone(), utxo.output.clone()))
+            .collect()
+    }
+
+    //spends the inputs of the block's transactions and adds their outputs. the block must have been validated
+    //against this set.
+    pub fn connect_block(&mut self, block: &Block) {
+        let mut spent = Vec::new();
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                if let Some(utxo) = self.utxos.remove(input) {
+                    spent.push((input.clone(), utxo));
+                }
+            }
+            if !transaction.is_reward {
+                self.nonces
+                    .insert(transaction.sender.clone(), transaction.nonce + 1);
+            }
+            for (outpoint, output) in transaction.created_outputs() {
+                self.utxos.insert(
+                    outpoint,
+                    Utxo {
+                        output: output.clone(),
+                        height: block.index,
+                        is_reward: transaction.is_reward,
+                    },
+                );
+            }
+        }
+        self.undo.insert(block.hash.clone(), (block.index, spent));
+        self.undo
+            .retain(|_, (height, _)| height.saturating_add(MAX_REORG_DEPTH) > block.index);
+    }
+
+    //undoes `connect_block` for the last block connected.
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<()> {
+        let (_, spent) = self
+            .undo
+            .remove(&block.hash)
+            .ok_or_else(|| anyhow!("no undo data for block {}", block.hash))?;
+        //outputs created and spent within the block come back with the spent ones, so remove the created ones last
+        self.utxos.extend(spent);
+        for transaction in &block.transactions {
+            for (outpoint, _) in transaction.created_outputs() {
+                self.utxos.remove(&outpoint);
+            }
+        }
+        //the block's first transaction from each sender carries the nonce it had before
+        for transaction in block.transactions.iter().rev() {
+            if transaction.is_reward {
+                continue;
+            }
+            if transaction.nonce == 0 {
+                self.nonces.remove(&transaction.sender);
+            } else {
+                self.nonces
+                    .insert(transaction.sender.clone(), transaction.nonce);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{Amount, Transaction, Wallet};
+
+    fn coins(amount: &str) -> Amount {
+        amount.parse().unwrap()
+    }
+
+    fn block(index: u64, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(index, transactions, &hex::encode([0u8; 32]));
+        block.merkle_root = block.calculate_merkle_root();
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    //pays `amount` from the sender's only output, the rest back to itself.
+    fn spend(wallet: &Wallet, from: OutPoint, has: &str, amount: &str, nonce: u64) -> Transaction {
+        let outputs = vec![
+            TxOutput::new(Wallet::new().address, coins(amount)),
+            TxOutput::new(
+                wallet.address.clone(),
+                coins(has).checked_sub(coins(amount)).unwrap(),
+            ),
+        ];
+        let mut transaction =
+            Transaction::new(wallet.address.clone(), vec![from], outputs).with_nonce(nonce);
+        transaction.sign(&wallet.signing_key, "chain").unwrap();
+        transaction
+    }
+
+    fn outpoint(transaction: &Transaction, vout: u32) -> OutPoint {
+        OutPoint {
+            txid: transaction.get_hash(),
+            vout,
+        }
+    }
+
+    #[test]
+    fn disconnecting_blocks_restores_outputs_and_nonces() {
+        let wallet = Wallet::new();
+        let premine =
+            Transaction::reward(0, vec![TxOutput::new(wallet.address.clone(), coins("100"))]);
+        let first = spend(&wallet, outpoint(&premine, 0), "100", "10", 0);
+        let second = spend(&wallet, outpoint(&first, 1), "90", "20", 1);
+        let genesis = block(0, vec![premine]);
+        let block1 = block(1, vec![Transaction::reward(1, Vec::new()), first.clone()]);
+        let block2 = block(2, vec![Transaction::reward(2, Vec::new()), second]);
+
+        let mut utxos = UtxoSet::new(10);
+        utxos.connect_block(&genesis);
+        let after_genesis = utxos.clone();
+        utxos.connect_block(&block1);
+        let after_block1 = utxos.clone();
+        utxos.connect_block(&block2);
+        assert_eq!(utxos.next_nonce(&wallet.address), 2);
+        assert!(utxos.get(&outpoint(&first, 1)).is_none());
+
+        utxos.disconnect_block(&block2).unwrap();
+        assert_eq!(utxos, after_block1);
+        assert_eq!(utxos.next_nonce(&wallet.address), 1);
+        utxos.disconnect_block(&block1).unwrap();
+        assert_eq!(utxos, after_genesis);
+        assert_eq!(utxos.next_nonce(&wallet.address), 0);
+        assert!(utxos.disconnect_block(&block1).is_err());
+    }
+
+    #[test]
+    fn rewards_mature_after_coinbase_maturity() {
+        let utxos = UtxoSet::new(10);
+        let output = TxOutput::new(String::from("miner"), coins("1"));
+        let reward = Utxo {
+            output: output.clone(),
+            height: 5,
+            is_reward: true,
+        };
+        assert!(!utxos.is_mature(&reward, 14));
+        assert!(utxos.is_mature(&reward, 15));
+        let premine = Utxo {
+            height: 0,
+            ..reward.clone()
+        };
+        assert!(utxos.is_mature(&premine, 1));
+        let transfer = Utxo {
+            is_reward: false,
+            ..reward
+        };
+        assert!(utxos.is_mature(&transfer, 6));
+        let far = Utxo {
+            output,
+            height: u64::MAX,
+            is_reward: true,
+        };
+        assert!(!UtxoSet::new(u64::MAX).is_mature(&far, u64::MAX - 1));
+    }
+
+    #[test]
+    fn undo_data_is_kept_only_within_the_reorg_horizon() {
+        let mut utxos = UtxoSet::new(10);
+        let blocks: Vec<Block> = (0..=MAX_REORG_DEPTH)
+            .map(|index| block(index, vec![Transaction::reward(index, Vec::new())]))
+            .collect();
+        for block in &blocks {
+            utxos.connect_block(block);
+        }
+        assert_eq!(utxos.undo.len(), MAX_REORG_DEPTH as usize);
+        assert!(!utxos.undo.contains_key(&blocks[0].hash));
+        assert!(utxos.disconnect_block(blocks.last().unwrap()).is_ok());
+    }
+}
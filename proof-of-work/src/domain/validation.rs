@@ -0,0 +1,598 @@
+use super::block::MAX_BLOCK_SIZE;
+use super::utxo_set::{Utxo, UtxoSet};
+use super::{Amount, Block, OutPoint, Target, Transaction, Wallet, BLOCK_VERSION};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+//a block's timestamp has to be later than the median timestamp of this many blocks before it.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+//and at most this far ahead of our clock.
+pub const MAX_FUTURE_DRIFT_SECS: u64 = 2 * 60 * 60;
+
+//the consensus rules a block can break.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    GenesisMismatch,
+    Version,
+    PreviousHash,
+    Index,
+    TimestampTooOld,
+    TimestampTooNew,
+    Hash,
+    MerkleRoot,
+    BlockSize,
+    Target,
+    ProofOfWork,
+    MissingReward,
+    MalformedReward,
+    RewardAmount,
+    ExtraReward,
+    NonPositiveAmount,
+    InvalidAddress,
+    AmountOverflow,
+    MissingSignature,
+    InvalidSignature,
+    Nonce,
+    MissingInput,
+    DoubleSpend,
+    InputOwner,
+    ImmatureReward,
+    InsufficientFunds,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Rule::GenesisMismatch => "genesis block does not match the chain spec",
+            Rule::Version => "block version is not supported",
+            Rule::PreviousHash => "previous hash does not match the parent block",
+            Rule::Index => "index does not follow the parent block",
+            Rule::TimestampTooOld => "timestamp is not after the median of the blocks before it",
+            Rule::TimestampTooNew => "timestamp is too far in the future",
+            Rule::Hash => "hash does not match the block contents",
+            Rule::MerkleRoot => "merkle root does not match the transactions",
+            Rule::BlockSize => "block is larger than the size limit",
+            Rule::Target => "target is not the one the chain history demands",
+            Rule::ProofOfWork => "hash does not meet the target",
+            Rule::MissingReward => "first transaction must be the mining reward",
+            Rule::MalformedReward => {
+                "mining reward must spend nothing and carry the block's height"
+            }
+            Rule::RewardAmount => "mining reward is not the subsidy plus the fees",
+            Rule::ExtraReward => "only the first transaction may be a reward",
+            Rule::NonPositiveAmount => "transaction amount must be positive",
+            Rule::InvalidAddress => "output address is not a public key",
+            Rule::AmountOverflow => "amounts add up to more than an amount can hold",
+            Rule::MissingSignature => "transaction is not signed",
+            Rule::InvalidSignature => {
+                "transaction signature does not match the sender on this chain"
+            }
+            Rule::Nonce => "nonce is not the sender's next sequence number",
+            Rule::MissingInput => "input is not an unspent output",
+            Rule::DoubleSpend => "output is spent twice",
+            Rule::InputOwner => "input does not belong to the sender",
+            Rule::ImmatureReward => "mining reward is spent before coinbase maturity",
+            Rule::InsufficientFunds => "inputs do not cover the outputs and the fee",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+//the first rule a chain breaks, and where.
+#[derive(Debug, Clone)]
+pub struct ValidationError {
+    pub block_index: u64,
+    pub tx_index: Option<usize>,
+    pub rule: Rule,
+    pub detail: String,
+}
+
+impl ValidationError {
+    pub fn block(block: &Block, rule: Rule, detail: String) -> Self {
+        Self {
+            block_index: block.index,
+            tx_index: None,
+            rule,
+            detail,
+        }
+    }
+
+    pub fn transaction(block: &Block, tx_index: usize, rule: Rule, detail: String) -> Self {
+        Self {
+            block_index: block.index,
+            tx_index: Some(tx_index),
+            rule,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {}", self.block_index)?;
+        if let Some(tx_index) = self.tx_index {
+            write!(f, ", transaction {}", tx_index)?;
+        }
+        write!(f, ": {}", self.rule)?;
+        if !self.detail.is_empty() {
+            write!(f, " ({})", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+//checks everything about a block that doesn't depend on balances: linkage, timestamp, hashes and proof of work
+//against `target`. `median_time_past` is that of the parent's branch, `now` our clock in unix seconds.
+pub fn check_header(
+    block: &Block,
+    parent: &Block,
+    target: Target,
+    median_time_past: u64,
+    now: u64,
+) -> Result<(), ValidationError> {
+    if block.version != BLOCK_VERSION {
+        return Err(ValidationError::block(
+            block,
+            Rule::Version,
+            block.version.to_string(),
+        ));
+    }
+    if block.previous_hash != parent.hash {
+        return Err(ValidationError::block(
+            block,
+            Rule::PreviousHash,
+            block.previous_hash.clone(),
+        ));
+    }
+    if block.index != parent.index + 1 {
+        return Err(ValidationError::block(
+            block,
+            Rule::Index,
+            format!("expected {}", parent.index + 1),
+        ));
+    }
+    if block.timestamp <= median_time_past {
+        return Err(ValidationError::block(
+            block,
+            Rule::TimestampTooOld,
+            format!("{}, median {}", block.timestamp, median_time_past),
+        ));
+    }
+    if block.timestamp > now.saturating_add(MAX_FUTURE_DRIFT_SECS) {
+        return Err(ValidationError::block(
+            block,
+            Rule::TimestampTooNew,
+            format!("{}, now {}", block.timestamp, now),
+        ));
+    }
+    if block.hash != block.calculate_hash() {
+        return Err(ValidationError::block(
+            block,
+            Rule::Hash,
+            block.hash.clone(),
+        ));
+    }
+    if block.merkle_root != block.calculate_merkle_root() {
+        return Err(ValidationError::block(
+            block,
+            Rule::MerkleRoot,
+            block.merkle_root.clone(),
+        ));
+    }
+    if block.size() > MAX_BLOCK_SIZE {
+        return Err(ValidationError::block(
+            block,
+            Rule::BlockSize,
+            format!("{} bytes, limit {}", block.size(), MAX_BLOCK_SIZE),
+        ));
+    }
+    if block.bits != target.to_compact() {
+        return Err(ValidationError::block(
+            block,
+            Rule::Target,
+            format!(
+                "expected bits {:08x}, got {:08x}",
+                target.to_compact(),
+                block.bits
+            ),
+        ));
+    }
+    if !block.meets_target() {
+        return Err(ValidationError::block(
+            block,
+            Rule::ProofOfWork,
+            format!("target {}", target),
+        ));
+    }
+
+    Ok(())
+}
+
+//median of the last MEDIAN_TIME_SPAN of `timestamps`, which are those of a branch, oldest first.
+pub fn median_time_past(timestamps: &[u64]) -> u64 {
+    let mut recent = timestamps[timestamps.len().saturating_sub(MEDIAN_TIME_SPAN)..].to_vec();
+    recent.sort_unstable();
+    recent.get(recent.len() / 2).copied().unwrap_or_default()
+}
+
+//what the transactions before one changed on top of the UTXO set: those earlier in the same block, or the pending
+//ones when a new transaction enters the pool.
+#[derive(Debug, Default)]
+pub struct StateOverlay {
+    created: HashMap<OutPoint, Utxo>,
+    spent: HashSet<OutPoint>,
+    nonces: HashMap<String, u64>,
+}
+
+impl StateOverlay {
+    //records a transaction that passed `check_transaction` for the block at `height`.
+    pub fn apply(&mut self, transaction: &Transaction, height: u64) {
+        self.spent.extend(transaction.inputs.iter().cloned());
+        for (outpoint, output) in transaction.created_outputs() {
+            let utxo = Utxo {
+                output: output.clone(),
+                height,
+                is_reward: false,
+            };
+            self.created.insert(outpoint, utxo);
+        }
+        self.nonces
+            .insert(transaction.sender.clone(), transaction.nonce + 1);
+    }
+
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.spent.contains(outpoint)
+    }
+
+    fn get<'a>(&'a self, outpoint: &OutPoint, utxos: &'a UtxoSet) -> Option<&'a Utxo> {
+        self.created.get(outpoint).or_else(|| utxos.get(outpoint))
+    }
+
+    pub fn next_nonce(&self, address: &str, utxos: &UtxoSet) -> u64 {
+        self.nonces
+            .get(address)
+            .copied()
+            .unwrap_or_else(|| utxos.next_nonce(address))
+    }
+}
+
+//checks the block's transactions against `utxos`, the chain state before it. a transaction may spend outputs of the
+//ones before it in the block. `subsidy` is what the monetary policy pays at the block's height, `chain_id` the
+//genesis hash signatures have to commit to.
+pub fn check_transactions(
+    block: &Block,
+    subsidy: Amount,
+    chain_id: &str,
+    utxos: &UtxoSet,
+) -> Result<(), ValidationError> {
+    let Some((reward, transactions)) = block.transactions.split_first() else {
+        return Err(ValidationError::block(
+            block,
+            Rule::MissingReward,
+            String::from("block has no transactions"),
+        ));
+    };
+    if !reward.is_reward {
+        return Err(ValidationError::transaction(
+            block,
+            0,
+            Rule::MissingReward,
+            String::new(),
+        ));
+    }
+    if !reward.inputs.is_empty() || reward.height != block.index {
+        return Err(ValidationError::transaction(
+            block,
+            0,
+            Rule::MalformedReward,
+            format!("{} inputs, height {}", reward.inputs.len(), reward.height),
+        ));
+    }
+    //reward outputs have to be spendable, just like those of transfers
+    if reward.outputs.iter().any(|output| output.amount.is_zero()) {
+        return Err(ValidationError::transaction(
+            block,
+            0,
+            Rule::NonPositiveAmount,
+            String::from("0"),
+        ));
+    }
+    if let Some(output) = reward
+        .outputs
+        .iter()
+        .find(|output| Wallet::decode_address(&output.address).is_err())
+    {
+        return Err(ValidationError::transaction(
+            block,
+            0,
+            Rule::InvalidAddress,
+            output.address.clone(),
+        ));
+    }
+    let (expected_reward, total_output) =
+        match (block_reward(subsidy, transactions), reward.total_output()) {
+            (Ok(expected_reward), Ok(total_output)) => (expected_reward, total_output),
+            (Err(err), _) | (_, Err(err)) => {
+                return Err(ValidationError::transaction(
+                    block,
+                    0,
+                    Rule::AmountOverflow,
+                    err.to_string(),
+                ));
+            }
+        };
+    if total_output != expected_reward {
+        return Err(ValidationError::transaction(
+            block,
+            0,
+            Rule::RewardAmount,
+            format!("expected {}, got {}", expected_reward, total_output),
+        ));
+    }
+
+    let mut overlay = StateOverlay::default();
+    for (tx_index, transaction) in (1..).zip(transactions) {
+        if transaction.is_reward {
+            return Err(ValidationError::transaction(
+                block,
+                tx_index,
+                Rule::ExtraReward,
+                String::new(),
+            ));
+        }
+        if let Err((rule, detail)) =
+            check_transaction(transaction, block.index, chain_id, utxos, &overlay)
+        {
+            return Err(ValidationError::transaction(block, tx_index, rule, detail));
+        }
+        overlay.apply(transaction, block.index);
+    }
+    Ok(())
+}
+
+//checks a transfer on its own and against the chain state it would be applied to in the block at `height`: `utxos`
+//with `overlay` on top. returns the rule it breaks, with a detail.
+pub fn check_transaction(
+    transaction: &Transaction,
+    height: u64,
+    chain_id: &str,
+    utxos: &UtxoSet,
+    overlay: &StateOverlay,
+) -> Result<(), (Rule, String)> {
+    check_transaction_form(transaction, chain_id)?;
+    check_transaction_state(transaction, height, utxos, overlay)
+}
+
+//the rules a transfer meets or breaks whatever the chain state: its outputs and its signature.
+fn check_transaction_form(transaction: &Transaction, chain_id: &str) -> Result<(), (Rule, String)> {
+    if transaction.outputs.is_empty() {
+        return Err((Rule::NonPositiveAmount, String::from("no outputs")));
+    }
+    if transaction
+        .outputs
+        .iter()
+        .any(|output| output.amount.is_zero())
+    {
+        return Err((Rule::NonPositiveAmount, String::from("0")));
+    }
+    if let Some(output) = transaction
+        .outputs
+        .iter()
+        .find(|output| Wallet::decode_address(&output.address).is_err())
+    {
+        return Err((Rule::InvalidAddress, output.address.clone()));
+    }
+    transaction
+        .total_cost()
+        .map_err(|err| (Rule::AmountOverflow, err.to_string()))?;
+    if transaction.signature.is_none() {
+        return Err((Rule::MissingSignature, String::new()));
+    }
+    if let Err(err) = transaction.verify(chain_id) {
+        return Err((Rule::InvalidSignature, err.to_string()));
+    }
+    Ok(())
+}
+
+//the rules that depend on the chain state: nonce, inputs and funds. enough on its own for a transfer that already
+//passed `check_transaction`, like a pending one, so its signature isn't verified again.
+pub fn check_transaction_state(
+    transaction: &Transaction,
+    height: u64,
+    utxos: &UtxoSet,
+    overlay: &StateOverlay,
+) -> Result<(), (Rule, String)> {
+    let total_cost = transaction
+        .total_cost()
+        .map_err(|err| (Rule::AmountOverflow, err.to_string()))?;
+    let next_nonce = overlay.next_nonce(&transaction.sender, utxos);
+    if transaction.nonce != next_nonce {
+        return Err((
+            Rule::Nonce,
+            format!("expected {}, got {}", next_nonce, transaction.nonce),
+        ));
+    }
+    if transaction.inputs.is_empty() {
+        return Err((Rule::MissingInput, String::from("no inputs")));
+    }
+
+    let mut total_input = Amount::ZERO;
+    let mut own_spent = HashSet::new();
+    for input in &transaction.inputs {
+        let outpoint = format!("{}:{}", input.txid, input.vout);
+        if overlay.is_spent(input) || !own_spent.insert(input) {
+            return Err((Rule::DoubleSpend, outpoint));
+        }
+        let Some(utxo) = overlay.get(input, utxos) else {
+            return Err((Rule::MissingInput, outpoint));
+        };
+        if utxo.output.address != transaction.sender {
+            return Err((Rule::InputOwner, outpoint));
+        }
+        if !utxos.is_mature(utxo, height) {
+            return Err((Rule::ImmatureReward, outpoint));
+        }
+        total_input = total_input
+            .checked_add(utxo.output.amount)
+            .map_err(|err| (Rule::AmountOverflow, err.to_string()))?;
+    }
+    if total_input < total_cost {
+        return Err((
+            Rule::InsufficientFunds,
+            format!("has {}, needs {}", total_input, total_cost),
+        ));
+    }
+    Ok(())
+}
+
+//what the miner of a block with these (non-reward) transactions is owed: the subsidy plus every fee.
+pub fn block_reward(subsidy: Amount, transactions: &[Transaction]) -> anyhow::Result<Amount> {
+    subsidy.checked_add(Amount::checked_sum(transactions.iter().map(|tx| tx.fee))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::TxOutput;
+
+    const CHAIN: &str = "chain";
+
+    fn coins(amount: &str) -> Amount {
+        amount.parse().unwrap()
+    }
+
+    fn block(index: u64, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(index, transactions, &hex::encode([0u8; 32]));
+        block.merkle_root = block.calculate_merkle_root();
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    fn outpoint(transaction: &Transaction, vout: u32) -> OutPoint {
+        OutPoint {
+            txid: transaction.get_hash(),
+            vout,
+        }
+    }
+
+    fn pay(wallet: &Wallet, inputs: Vec<OutPoint>, amount: &str, nonce: u64) -> Transaction {
+        let outputs = vec![TxOutput::new(Wallet::new().address, coins(amount))];
+        let mut transaction =
+            Transaction::new(wallet.address.clone(), inputs, outputs).with_nonce(nonce);
+        transaction.sign(&wallet.signing_key, CHAIN).unwrap();
+        transaction
+    }
+
+    //a utxo set holding a 100 coin premine of `wallet` and a 50 coin reward it got in block 1.
+    fn funded(wallet: &Wallet) -> (UtxoSet, Transaction, Transaction) {
+        let premine =
+            Transaction::reward(0, vec![TxOutput::new(wallet.address.clone(), coins("100"))]);
+        let reward =
+            Transaction::reward(1, vec![TxOutput::new(wallet.address.clone(), coins("50"))]);
+        let mut utxos = UtxoSet::new(100);
+        utxos.connect_block(&block(0, vec![premine.clone()]));
+        utxos.connect_block(&block(1, vec![reward.clone()]));
+        (utxos, premine, reward)
+    }
+
+    #[test]
+    fn median_time_past_looks_at_the_last_eleven_blocks() {
+        assert_eq!(median_time_past(&[]), 0);
+        assert_eq!(median_time_past(&[5]), 5);
+        assert_eq!(median_time_past(&[3, 1, 2]), 2);
+        let timestamps: Vec<u64> = (0..20).collect();
+        assert_eq!(median_time_past(&timestamps), 14);
+    }
+
+    #[test]
+    fn header_timestamp_has_to_be_after_the_median_and_not_too_far_ahead() {
+        let parent = Block::new(0, Vec::new(), &hex::encode([0u8; 32]));
+        let mut block = Block::new(1, Vec::new(), &parent.hash);
+        let now = 1_000_000;
+
+        block.timestamp = 100;
+        let err = check_header(&block, &parent, Target::MAX, 100, now).unwrap_err();
+        assert_eq!(err.rule, Rule::TimestampTooOld);
+
+        block.timestamp = now + MAX_FUTURE_DRIFT_SECS + 1;
+        let err = check_header(&block, &parent, Target::MAX, 100, now).unwrap_err();
+        assert_eq!(err.rule, Rule::TimestampTooNew);
+
+        //a valid timestamp gets as far as the hash check
+        block.timestamp = now + MAX_FUTURE_DRIFT_SECS;
+        let err = check_header(&block, &parent, Target::MAX, 100, now).unwrap_err();
+        assert_eq!(err.rule, Rule::Hash);
+    }
+
+    #[test]
+    fn an_output_spent_by_a_pending_transaction_cant_be_spent_again() {
+        let wallet = Wallet::new();
+        let (utxos, premine, _) = funded(&wallet);
+        let first = pay(&wallet, vec![outpoint(&premine, 0)], "10", 0);
+        let mut overlay = StateOverlay::default();
+        assert!(check_transaction(&first, 2, CHAIN, &utxos, &overlay).is_ok());
+        overlay.apply(&first, 2);
+
+        let second = pay(&wallet, vec![outpoint(&premine, 0)], "20", 1);
+        let (rule, _) = check_transaction(&second, 2, CHAIN, &utxos, &overlay).unwrap_err();
+        assert_eq!(rule, Rule::DoubleSpend);
+        let twice = pay(&wallet, vec![outpoint(&premine, 0); 2], "20", 0);
+        let (rule, _) =
+            check_transaction(&twice, 2, CHAIN, &utxos, &StateOverlay::default()).unwrap_err();
+        assert_eq!(rule, Rule::DoubleSpend);
+    }
+
+    #[test]
+    fn a_block_cant_spend_an_output_twice() {
+        let wallet = Wallet::new();
+        let miner = Wallet::new();
+        let (utxos, premine, _) = funded(&wallet);
+        let first = pay(&wallet, vec![outpoint(&premine, 0)], "10", 0);
+        let second = pay(&wallet, vec![outpoint(&premine, 0)], "20", 1);
+        let reward = Transaction::reward(2, vec![TxOutput::new(miner.address.clone(), coins("1"))]);
+        let block = block(2, vec![reward, first, second]);
+
+        let err = check_transactions(&block, coins("1"), CHAIN, &utxos).unwrap_err();
+        assert_eq!(
+            (err.block_index, err.tx_index, err.rule),
+            (2, Some(2), Rule::DoubleSpend)
+        );
+    }
+
+    #[test]
+    fn rewards_are_spendable_only_after_coinbase_maturity() {
+        let wallet = Wallet::new();
+        let (utxos, _, reward) = funded(&wallet);
+        let spend = pay(&wallet, vec![outpoint(&reward, 0)], "10", 0);
+        let overlay = StateOverlay::default();
+
+        let (rule, _) = check_transaction(&spend, 100, CHAIN, &utxos, &overlay).unwrap_err();
+        assert_eq!(rule, Rule::ImmatureReward);
+        assert!(check_transaction(&spend, 101, CHAIN, &utxos, &overlay).is_ok());
+    }
+
+    #[test]
+    fn reward_outputs_have_to_be_positive_and_pay_a_key() {
+        let wallet = Wallet::new();
+        let (utxos, _, _) = funded(&wallet);
+        for (output, expected) in [
+            (
+                TxOutput::new(String::from("notakey"), coins("1")),
+                Rule::InvalidAddress,
+            ),
+            (
+                TxOutput::new(wallet.address.clone(), Amount::ZERO),
+                Rule::NonPositiveAmount,
+            ),
+        ] {
+            let reward = Transaction::reward(2, vec![output]);
+            let err =
+                check_transactions(&block(2, vec![reward]), coins("1"), CHAIN, &utxos).unwrap_err();
+            assert_eq!((err.tx_index, err.rule), (Some(0), expected));
+        }
+        let paid = Transaction::reward(2, vec![TxOutput::new(wallet.address.clone(), coins("1"))]);
+        assert!(check_transactions(&block(2, vec![paid]), coins("1"), CHAIN, &utxos).is_ok());
+    }
+}
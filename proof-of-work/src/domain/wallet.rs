@@ -1,6 +1,6 @@
 use super::Transaction;
 use crate::util::sign_helper;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use hex;
 
@@ -22,7 +22,30 @@ impl Wallet {
         }
     }
 
-    pub fn sign_transaction(&self, transaction: &mut Transaction) -> Result<()> {
-        transaction.sign(&self.signing_key)
+    //restores a wallet from its hex-encoded 32-byte secret key.
+    pub fn from_secret_key(secret_key: &str) -> Result<Self> {
+        let bytes: [u8; 32] = hex::decode(secret_key)?
+            .try_into()
+            .map_err(|_| anyhow!("Secret key must be exactly 32 bytes"))?;
+        let signing_key = SigningKey::from_bytes(&bytes);
+        let verifying_key = signing_key.verifying_key();
+
+        Ok(Self {
+            address: hex::encode(verifying_key.as_bytes()),
+            signing_key,
+            verifying_key,
+        })
+    }
+
+    //an address is the hex-encoded public key of its wallet.
+    pub fn decode_address(address: &str) -> Result<VerifyingKey> {
+        let bytes: [u8; 32] = hex::decode(address)?
+            .try_into()
+            .map_err(|_| anyhow!("Address must be a 32-byte public key"))?;
+        Ok(VerifyingKey::from_bytes(&bytes)?)
+    }
+
+    pub fn sign_transaction(&self, transaction: &mut Transaction, chain_id: &str) -> Result<()> {
+        transaction.sign(&self.signing_key, chain_id)
     }
 }
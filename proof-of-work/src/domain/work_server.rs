@@ -0,0 +1,370 @@
+use super::miner::Miner;
+use super::{Block, BlockHeader, Blockchain, Target, Wallet};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+//how long an external miner works on one template before asking for a fresh one.
+pub const WORK_REFRESH_SECS: u64 = 10;
+//templates handed out and not yet submitted; the oldest is forgotten when there are more on the current tip.
+const MAX_JOBS: usize = 64;
+//longest request line accepted, newline excluded; a miner that sends more is disconnected.
+const MAX_REQUEST_LINE: usize = 4096;
+
+//getwork protocol: one JSON request per line from the miner, answered by one JSON response per line.
+//  {"method":"getwork","params":{"address":"<coinbase address or null>"}}
+//  {"method":"submit","params":{"job_id":"..","timestamp":..,"nonce":..}}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum WorkRequest {
+    GetWork {
+        address: Option<String>,
+    },
+    Submit {
+        job_id: String,
+        timestamp: u64,
+        nonce: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "lowercase")]
+pub enum WorkResponse {
+    Work(WorkTemplate),
+    Accepted { hash: String },
+    Error { reason: String },
+}
+
+//everything a miner needs: the header to vary the timestamp and nonce of, and the target its hash has to meet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkTemplate {
+    pub job_id: String,
+    pub version: u32,
+    pub height: u64,
+    pub previous_hash: String,
+    pub merkle_root: String,
+    pub timestamp: u64,
+    pub bits: u32,
+    pub target: String,
+    pub coinbase_address: String, //receives the mining reward
+    pub header: String,           //the encoded header, hex
+}
+
+//serves block templates to miners running outside the node and connects the blocks they solve.
+#[derive(Clone)]
+pub struct WorkServer {
+    blockchain: Arc<Mutex<Blockchain>>,
+    coinbase_address: String, //used when the miner doesn't name one
+    jobs: Arc<Mutex<HashMap<String, (u64, Block)>>>, //job id -> (sequence number, template)
+    next_job_seq: Arc<AtomicU64>,
+}
+
+impl WorkServer {
+    pub fn new(blockchain: Arc<Mutex<Blockchain>>, coinbase_address: String) -> Self {
+        Self {
+            blockchain,
+            coinbase_address,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            next_job_seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub async fn start_listening(&self, bind_addr: &str) -> Result<tokio::task::JoinHandle<()>> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        println!("Serving getwork for external miners on {}", bind_addr);
+
+        let server = self.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                if let Ok((stream, addr)) = listener.accept().await {
+                    println!("Miner connected from {}", addr);
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = server.handle_miner(stream).await {
+                            eprintln!("Error: Miner {} disconnected: {}", addr, err);
+                        }
+                    });
+                }
+            }
+        }))
+    }
+
+    async fn handle_miner(&self, stream: TcpStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            //reading one byte past the limit tells a line that is too long from one that just fits
+            line.clear();
+            let read = (&mut reader)
+                .take(MAX_REQUEST_LINE as u64 + 1)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if read == 0 {
+                return Ok(());
+            }
+            if line.last() != Some(&b'\n') && line.len() > MAX_REQUEST_LINE {
+                let reason = format!("request line longer than {} bytes", MAX_REQUEST_LINE);
+                let response = WorkResponse::Error {
+                    reason: reason.clone(),
+                };
+                let _ = write_response(&mut writer, &response).await;
+                return Err(anyhow!(reason));
+            }
+
+            let response = match serde_json::from_slice::<WorkRequest>(&line) {
+                Ok(request) => self.handle_request(request).await,
+                Err(err) => WorkResponse::Error {
+                    reason: format!("malformed request: {}", err),
+                },
+            };
+            write_response(&mut writer, &response).await?;
+        }
+    }
+
+    async fn handle_request(&self, request: WorkRequest) -> WorkResponse {
+        let result = match request {
+            WorkRequest::GetWork { address } => {
+                self.get_work(address).await.map(WorkResponse::Work)
+            }
+            WorkRequest::Submit {
+                job_id,
+                timestamp,
+                nonce,
+            } => self
+                .submit(&job_id, timestamp, nonce)
+                .await
+                .map(|hash| WorkResponse::Accepted { hash }),
+        };
+        result.unwrap_or_else(|err| WorkResponse::Error {
+            reason: err.to_string(),
+        })
+    }
+
+    async fn get_work(&self, address: Option<String>) -> Result<WorkTemplate> {
+        let coinbase_address = address.unwrap_or_else(|| self.coinbase_address.clone());
+        Wallet::decode_address(&coinbase_address)
+            .map_err(|err| anyhow!("invalid coinbase address: {}", err))?;
+        let (template, tip) = {
+            let blockchain = self.blockchain.lock().await;
+            let template = blockchain
+                .create_block_template(coinbase_address.clone())
+                .ok_or_else(|| anyhow!("no transactions to mine"))?;
+            (template, blockchain.chain.last().unwrap().hash.clone())
+        };
+
+        //random so that nobody but the miner it was handed to can submit to a job
+        let job_id = hex::encode(rand::random::<[u8; 16]>());
+        let seq = self.next_job_seq.fetch_add(1, Ordering::Relaxed);
+        let header = template.header();
+        let work = WorkTemplate {
+            job_id: job_id.clone(),
+            version: header.version,
+            height: header.height,
+            previous_hash: template.previous_hash.clone(),
+            merkle_root: template.merkle_root.clone(),
+            timestamp: header.timestamp,
+            bits: header.bits,
+            target: Target::from_compact(header.bits)?.to_string(),
+            coinbase_address,
+            header: hex::encode(header.encode()),
+        };
+
+        //jobs on an old tip can't be connected any more
+        let mut jobs = self.jobs.lock().await;
+        jobs.retain(|_, (_, job)| job.previous_hash == tip);
+        if jobs.len() >= MAX_JOBS {
+            let oldest = jobs
+                .iter()
+                .min_by_key(|(_, (seq, _))| *seq)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                jobs.remove(&oldest);
+            }
+        }
+        jobs.insert(job_id, (seq, template));
+        Ok(work)
+    }
+
+    //rebuilds the block of the job with the miner's timestamp and nonce, then validates, connects and relays it.
+    //the job is kept until a solution for it is accepted, so a bad share doesn't cost the miner its template.
+    async fn submit(&self, job_id: &str, timestamp: u64, nonce: u64) -> Result<String> {
+        let mut block = self
+            .jobs
+            .lock()
+            .await
+            .get(job_id)
+            .map(|(_, block)| block.clone())
+            .ok_or_else(|| anyhow!("unknown or stale job {}", job_id))?;
+        block.timestamp = timestamp;
+        block.nonce = nonce;
+        block.hash = block.calculate_hash();
+
+        let mut blockchain = self.blockchain.lock().await;
+        if block.previous_hash != blockchain.chain.last().unwrap().hash {
+            return Err(anyhow!("stale job {}: the chain tip has moved", job_id));
+        }
+        blockchain.submit_block(block.clone()).await?;
+        drop(blockchain);
+        self.jobs.lock().await.remove(job_id);
+        println!(
+            "Block {} from an external miner accepted: {}",
+            block.index, block.hash
+        );
+        Ok(block.hash)
+    }
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &WorkResponse,
+) -> Result<()> {
+    let mut bytes = serde_json::to_vec(response)?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    Ok(())
+}
+
+//runs `miner` against a node's getwork interface until the connection drops, asking for fresh work every
+//WORK_REFRESH_SECS so it doesn't keep mining on a tip that has moved.
+pub async fn mine_remote(addr: &str, miner: &Miner, address: Option<String>) -> Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    println!(
+        "Mining for the node at {} on {} threads",
+        addr,
+        miner.threads()
+    );
+
+    loop {
+        let request = WorkRequest::GetWork {
+            address: address.clone(),
+        };
+        let work = match call(&mut writer, &mut lines, &request).await? {
+            WorkResponse::Work(work) => work,
+            WorkResponse::Error { reason } => {
+                println!("No work available: {}", reason);
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            other => return Err(anyhow!("unexpected response {:?}", other)),
+        };
+        let header = BlockHeader::decode(&hex::decode(&work.header)?)?;
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let timer = tokio::spawn({
+            let cancel = Arc::clone(&cancel);
+            async move {
+                sleep(Duration::from_secs(WORK_REFRESH_SECS)).await;
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        let worker_miner = miner.clone();
+        let worker_cancel = Arc::clone(&cancel);
+        let solved =
+            tokio::task::spawn_blocking(move || worker_miner.mine_header(header, &worker_cancel))
+                .await?;
+        timer.abort();
+
+        let Some((header, stats)) = solved else {
+            continue; //refresh the template
+        };
+        println!(
+            "Solved job {} at height {} ({:.1} kH/s)",
+            work.job_id,
+            work.height,
+            stats.hashrate() / 1000.0
+        );
+        let request = WorkRequest::Submit {
+            job_id: work.job_id,
+            timestamp: header.timestamp,
+            nonce: header.nonce,
+        };
+        match call(&mut writer, &mut lines, &request).await? {
+            WorkResponse::Accepted { hash } => println!("Block accepted: {}", hash),
+            WorkResponse::Error { reason } => println!("Block rejected: {}", reason),
+            other => return Err(anyhow!("unexpected response {:?}", other)),
+        }
+    }
+}
+
+async fn call(
+    writer: &mut tokio::net::tcp::OwnedWriteHalf,
+    lines: &mut tokio::io::Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+    request: &WorkRequest,
+) -> Result<WorkResponse> {
+    let mut bytes = serde_json::to_vec(request)?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    let line = lines
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("connection closed"))?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{Allocation, ChainSpec, Transaction, TxOutput};
+
+    //a server whose chain has a pending transaction, so there is a block to mine.
+    async fn server() -> WorkServer {
+        let wallet = Wallet::new();
+        let spec = ChainSpec {
+            premine: vec![Allocation {
+                address: wallet.address.clone(),
+                amount: "100".parse().unwrap(),
+            }],
+            ..ChainSpec::default()
+        };
+        let mut blockchain = Blockchain::new(spec).unwrap();
+
+        let inputs = blockchain
+            .get_spendable_outputs(&wallet.address)
+            .into_iter()
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        let outputs = vec![TxOutput::new(Wallet::new().address, "99".parse().unwrap())];
+        let mut transaction = Transaction::new(wallet.address.clone(), inputs, outputs);
+        let chain_id = blockchain.get_genesis_hash().to_string();
+        wallet
+            .sign_transaction(&mut transaction, &chain_id)
+            .unwrap();
+        blockchain.add_transaction(transaction).await.unwrap();
+
+        WorkServer::new(Arc::new(Mutex::new(blockchain)), Wallet::new().address)
+    }
+
+    #[tokio::test]
+    async fn the_oldest_job_is_evicted_first() {
+        let server = server().await;
+        let mut ids = Vec::new();
+        for _ in 0..=MAX_JOBS {
+            ids.push(server.get_work(None).await.unwrap().job_id);
+        }
+
+        let jobs = server.jobs.lock().await;
+        assert_eq!(jobs.len(), MAX_JOBS);
+        assert!(!jobs.contains_key(&ids[0]));
+        assert!(ids[1..].iter().all(|id| jobs.contains_key(id)));
+        assert!(ids.iter().all(|id| id.len() == 32));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_solution_keeps_the_job() {
+        let server = server().await;
+        let job_id = server.get_work(None).await.unwrap().job_id;
+
+        //a timestamp before the genesis block can't be valid
+        assert!(server.submit(&job_id, 0, 0).await.is_err());
+        assert!(server.jobs.lock().await.contains_key(&job_id));
+    }
+}
@@ -1,14 +1,40 @@
 #![allow(warnings)]
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, Ok, Result};
 mod domain;
 pub mod util;
 use clap::{builder::Str, Arg, ArgMatches, Command};
-use domain::{Blockchain, Network, Transaction, Wallet};
+use domain::miner::{self, Miner};
+use domain::work_server;
+use domain::{
+    sync, Amount, Blockchain, ChainSpec, InboundMessage, Network, Transaction, Wallet, WorkServer,
+};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::{sleep, Duration};
 use util::converter;
+
+//secret key of the address premined by the bundled chain-spec.json, so the demo loop has funds to move around.
+//it is public on purpose: never premine to it outside a local test network.
+const DEMO_FAUCET_KEY: &str = "706c61792d636861696e2d64656d6f2d6661756365742d6b65792d3030303031";
+const PEER_DISCOVERY_INTERVAL_SECS: u64 = 30;
+const BENCH_MINING_SECS: u64 = 5;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = get_args();
+    if args.get_flag("bench-mining") {
+        miner::bench_mining(&get_miner(&args), Duration::from_secs(BENCH_MINING_SECS));
+        return Ok(());
+    }
+    if let Some(node_addr) = args.get_one::<String>("getwork") {
+        let address = match args.get_one::<String>("coinbase-address") {
+            Some(_) => Some(get_coinbase_address(&args)?),
+            None => None, //the node's own
+        };
+        return work_server::mine_remote(node_addr, &get_miner(&args), address).await;
+    }
+
     let listen_addr = args.get_one::<String>("listen").unwrap().clone();
     let peers_addr = args
         .get_one::<String>("peers")
@@ -16,57 +42,188 @@ async fn main() -> Result<()> {
         .split(',')
         .collect::<Vec<&str>>();
 
-    let mut blockchain = Blockchain::new(2, 10).unwrap();
+    let chain_spec = match args.get_one::<String>("chain-spec") {
+        Some(path) => ChainSpec::load(path)?,
+        None => ChainSpec::default(),
+    };
+
+    let mut blockchain = Blockchain::new(chain_spec).unwrap();
     let mut network = Network::new();
+    if let Some(max_frame_size) = args.get_one::<usize>("max-frame-size") {
+        network.set_max_frame_size(*max_frame_size);
+    }
+    if let Some(ban_threshold) = args.get_one::<u32>("ban-threshold") {
+        network.set_ban_policy(
+            *ban_threshold,
+            *args.get_one::<i64>("ban-duration").unwrap(),
+        );
+    }
+    network.set_network_id(blockchain.get_genesis_hash());
+    network.set_listen_addr(listen_addr.clone()).await;
+    let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+    network.set_inbound(inbound_tx);
     network
         .add_peers(converter::convert_vec_of_str_to_vec_of_string(peers_addr))
         .await;
     blockchain.set_network(network.clone());
+    let blockchain = Arc::new(Mutex::new(blockchain));
+
+    network.start_listening(&listen_addr).await;
+    tokio::spawn(handle_inbound(Arc::clone(&blockchain), inbound_rx));
+    tokio::spawn(handle_console(network.clone()));
 
+    let miner_addr = get_coinbase_address(&args)?;
+    if let Some(work_addr) = args.get_one::<String>("work-listen") {
+        WorkServer::new(Arc::clone(&blockchain), miner_addr.clone())
+            .start_listening(work_addr)
+            .await?;
+    }
+
+    //learn the rest of the network from the given peers, then catch up with its chain before mining on top of it
+    network.discover_peers().await;
+    sync::synchronize(&blockchain, &network).await;
+
+    let discovery_network = network.clone();
     tokio::spawn(async move {
-        network.start_listening(&listen_addr).await;
+        loop {
+            sleep(Duration::from_secs(PEER_DISCOVERY_INTERVAL_SECS)).await;
+            discovery_network.discover_peers().await;
+        }
     });
 
+    let miner = get_miner(&args);
+    println!("Mining on {} threads", miner.threads());
+
+    let faucet_wallet = Wallet::from_secret_key(DEMO_FAUCET_KEY)?;
+    let chain_id = blockchain.lock().await.get_genesis_hash().to_string();
     let mut block_counter = 1;
     loop {
         let alice_wallet = Wallet::new();
         let bob_wallet: Wallet = Wallet::new();
-        let mut alice_transaction =
-            Transaction::new(alice_wallet.address.clone(), bob_wallet.address, 30.0);
 
-        alice_wallet.sign_transaction(&mut alice_transaction)?;
+        if blockchain.lock().await.needs_sync {
+            sync::synchronize(&blockchain, &network).await;
+        }
 
-        if let Err(err) = alice_transaction.verify(&alice_wallet.verifying_key) {
-            println!("Transaction verification failed: {}", err);
-        } else {
-            println!("Transaction signature verified!");
-            blockchain.add_transaction(alice_transaction).await?;
+        //fund alice from the premined faucet
+        match new_transfer(
+            &blockchain,
+            &faucet_wallet.address,
+            alice_wallet.address.clone(),
+            "100".parse()?,
+            "0.01".parse()?,
+        )
+        .await
+        {
+            Result::Ok(mut funding_transaction) => {
+                faucet_wallet.sign_transaction(&mut funding_transaction, &chain_id)?;
+                if let Err(err) = blockchain
+                    .lock()
+                    .await
+                    .add_transaction(funding_transaction)
+                    .await
+                {
+                    println!("{}", err);
+                }
+            }
+            Err(err) => println!("{}", err),
+        }
+        miner::mine_next_block(&blockchain, &miner, &miner_addr).await;
+
+        match new_transfer(
+            &blockchain,
+            &alice_wallet.address,
+            bob_wallet.address,
+            "30".parse()?,
+            "0.02".parse()?,
+        )
+        .await
+        {
+            Result::Ok(mut alice_transaction) => {
+                alice_wallet.sign_transaction(&mut alice_transaction, &chain_id)?;
+
+                if let Err(err) = alice_transaction.verify(&chain_id) {
+                    println!("Transaction verification failed: {}", err);
+                } else {
+                    println!("Transaction signature verified!");
+                    if let Err(err) = blockchain
+                        .lock()
+                        .await
+                        .add_transaction(alice_transaction.clone())
+                        .await
+                    {
+                        println!("{}", err);
+                    }
+                }
+                miner::mine_next_block(&blockchain, &miner, &miner_addr).await;
+
+                //a light client only needs the block's merkle root to check the transaction was included
+                let tx_hash = alice_transaction.get_hash();
+                if let Some((block, proof)) =
+                    blockchain.lock().await.get_transaction_proof(&tx_hash)
+                {
+                    if proof.verify(&tx_hash, &block.merkle_root) {
+                        println!(
+                            "Merkle proof of alice's transaction verified against block {}",
+                            block.index
+                        );
+                    }
+                }
+            }
+            Err(err) => println!("{}", err),
         }
-        let miner_addr = String::from("miner_addr");
-        blockchain.add_block(miner_addr.clone());
 
-        let john_wallet = Wallet::new();
         let chris_wallet: Wallet = Wallet::new();
 
-        blockchain
-            .add_transaction(Transaction::new(
-                john_wallet.address,
-                chris_wallet.address,
-                70.0,
-            ))
-            .await?; //skip sign_transaction and verify as it's a sample.
-        blockchain.add_block(miner_addr.clone());
+        match new_transfer(
+            &blockchain,
+            &faucet_wallet.address,
+            chris_wallet.address,
+            "70".parse()?,
+            Amount::ZERO,
+        )
+        .await
+        {
+            Result::Ok(mut chris_transaction) => {
+                faucet_wallet.sign_transaction(&mut chris_transaction, &chain_id)?;
+                if let Err(err) = blockchain
+                    .lock()
+                    .await
+                    .add_transaction(chris_transaction)
+                    .await
+                {
+                    println!("{}", err);
+                }
+            }
+            Err(err) => println!("{}", err),
+        }
+        miner::mine_next_block(&blockchain, &miner, &miner_addr).await;
 
-        blockchain.add_block(miner_addr.clone()); //no transaction sample
+        miner::mine_next_block(&blockchain, &miner, &miner_addr).await; //no transaction sample
 
         //blockchain.print_chain();
 
-        if blockchain.is_valid_chain() {
+        if blockchain.lock().await.is_valid_chain() {
             println!("The blockchain is valid.");
         } else {
             println!("The blockchain is invalid!");
         }
 
+        {
+            let blockchain = blockchain.lock().await;
+            let height = blockchain.chain.len() as u64 - 1;
+            println!(
+                "Circulating supply at block {}: {} (next subsidy {})",
+                height,
+                blockchain
+                    .get_circulating_supply(height)
+                    .unwrap_or_default(),
+                blockchain.get_subsidy(height + 1)
+            );
+        }
+
+        print_peers(&network).await;
+
         println!("Block Counter: {block_counter} Completed. ---------------------------------------------------------------------------------------------------");
         sleep(Duration::from_secs(5)).await;
         block_counter = block_counter + 1;
@@ -75,34 +232,188 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+//an unsigned transaction paying `amount` to `receiver` from the outputs `sender` can spend on our chain.
+async fn new_transfer(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    sender: &str,
+    receiver: String,
+    amount: Amount,
+    fee: Amount,
+) -> Result<Transaction> {
+    let (unspent, nonce) = {
+        let blockchain = blockchain.lock().await;
+        (
+            blockchain.get_spendable_outputs(sender),
+            blockchain.get_next_nonce(sender),
+        )
+    };
+    Ok(
+        Transaction::transfer(sender.to_string(), &unspent, receiver, amount, fee)?
+            .with_nonce(nonce),
+    )
+}
+
+//hands each chain-level message received by the network to the blockchain and sends back its reply.
+async fn handle_inbound(
+    blockchain: Arc<Mutex<Blockchain>>,
+    mut inbound_rx: mpsc::UnboundedReceiver<InboundMessage>,
+) {
+    while let Some(inbound) = inbound_rx.recv().await {
+        let reply = blockchain
+            .lock()
+            .await
+            .handle_message(&inbound.from, inbound.message)
+            .await;
+        let _ = inbound.reply.send(reply);
+    }
+}
+
+//operator commands read from stdin: `peers`, `bans`, `unban <host>` and `clearbans`.
+async fn handle_console(network: Network) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Result::Ok(Some(line)) = lines.next_line().await {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["peers"] => print_peers(&network).await,
+            ["bans"] => print_bans(&network).await,
+            ["unban", host] => {
+                if network.unban(host).await {
+                    println!("Unbanned {}", host);
+                } else {
+                    println!("{} is not banned", host);
+                }
+            }
+            ["clearbans"] => {
+                network.clear_bans().await;
+                println!("Cleared all bans");
+            }
+            [] => {}
+            _ => println!(
+                "Unknown command: {} (try peers, bans, unban <host>, clearbans)",
+                line
+            ),
+        }
+    }
+}
+
+async fn print_bans(network: &Network) {
+    let bans = network.get_bans().await;
+    if bans.is_empty() {
+        println!("No banned peers");
+    }
+    for ban in bans {
+        println!(
+            "Banned {} until {} ({})",
+            ban.host,
+            ban.banned_until.to_rfc3339(),
+            ban.reason
+        );
+    }
+}
+
+async fn print_peers(network: &Network) {
+    for peer in network.get_peer_infos().await {
+        println!(
+            "Peer {} {:?} {:?} height:{} last seen:{}",
+            peer.addr,
+            peer.direction,
+            peer.state,
+            peer.best_height,
+            peer.last_seen
+                .map_or(String::from("never"), |time| time.to_rfc3339())
+        );
+    }
+}
+
+//the --coinbase-address if it is a public key, else a new wallet whose key is printed so its rewards can be spent.
+fn get_coinbase_address(args: &ArgMatches) -> Result<String> {
+    if let Some(address) = args.get_one::<String>("coinbase-address") {
+        Wallet::decode_address(address)
+            .map_err(|err| anyhow!("Invalid coinbase address {}: {}", address, err))?;
+        return Ok(address.clone());
+    }
+    let wallet = Wallet::new();
+    println!(
+        "Mining rewards go to new wallet {} with secret key {}",
+        wallet.address,
+        hex::encode(wallet.signing_key.to_bytes())
+    );
+    Ok(wallet.address)
+}
+
+fn get_miner(args: &ArgMatches) -> Miner {
+    match args.get_one::<usize>("mining-threads") {
+        Some(threads) => Miner::new(*threads),
+        None => Miner::default(),
+    }
+}
+
 fn get_args() -> ArgMatches {
     let app = Command::new("Blockchain Peer")
         .arg(
             Arg::new("listen")
                 .help("The address to listen on")
-                .required(true)
+                .required_unless_present_any(["bench-mining", "getwork"])
                 .index(1),
         )
         .arg(
             Arg::new("peers")
                 .help("Comma-separated list of peer addresses")
-                .required(true)
+                .required_unless_present_any(["bench-mining", "getwork"])
                 .index(2),
+        )
+        .arg(
+            Arg::new("chain-spec")
+                .long("chain-spec")
+                .help("Path to the chain spec JSON file; defaults to the bundled chain-spec.json"),
+        )
+        .arg(
+            Arg::new("max-frame-size")
+                .long("max-frame-size")
+                .help("Largest network message accepted or sent, in bytes")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("mining-threads")
+                .long("mining-threads")
+                .help("Number of mining threads; defaults to one per CPU")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("bench-mining")
+                .long("bench-mining")
+                .help("Measure the mining hashrate and exit")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("work-listen").long("work-listen").help(
+                "Address to serve block templates to external miners on (getwork JSON over TCP)",
+            ),
+        )
+        .arg(
+            Arg::new("getwork")
+                .long("getwork")
+                .help("Mine for the node serving getwork at this address instead of running a node")
+                .conflicts_with("bench-mining"),
+        )
+        .arg(
+            Arg::new("coinbase-address").long("coinbase-address").help(
+                "Public key address that receives the mining rewards; defaults to a new wallet",
+            ),
+        )
+        .arg(
+            Arg::new("ban-threshold")
+                .long("ban-threshold")
+                .help("Misbehavior score at which a peer gets banned")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("ban-duration")
+                .long("ban-duration")
+                .help("How long a banned peer stays banned, in seconds")
+                .value_parser(clap::value_parser!(i64))
+                .default_value("86400"),
         );
     app.get_matches()
 }
-
-fn invalidate_chain_Sample(blockchain: &mut Blockchain) {
-    let transactions = vec![Transaction::new(
-        String::from("..."),
-        String::from("..."),
-        100.0,
-    )];
-    blockchain.chain[1].transactions = transactions;
-    blockchain.chain[1].hash = blockchain.chain[1].calculate_hash();
-    if blockchain.is_valid_chain() {
-        println!("The blockchain is valid.");
-    } else {
-        println!("The blockchain is invalid!");
-    }
-}